@@ -0,0 +1,43 @@
+//! CRC32C (Castagnoli), the checksum of the journal and of the metadata
+
+const POLY: u32 = 0x82f6_3b78;
+
+const TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ POLY } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+/// go on with the checksum `crc` of the bytes before `data`, start with 0
+pub const fn crc32c(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    let mut i = 0;
+    while i < data.len() {
+        crc = TABLE[((crc ^ data[i] as u32) & 0xff) as usize] ^ (crc >> 8);
+        i += 1;
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check() {
+        assert_eq!(crc32c(0, b""), 0);
+        assert_eq!(crc32c(0, b"123456789"), 0xe306_9283);
+        // in pieces, the same as at once
+        assert_eq!(crc32c(crc32c(0, b"1234"), b"56789"), 0xe306_9283);
+    }
+}
@@ -0,0 +1,274 @@
+//! Extent trees, mapping runs of file blocks to runs of disk blocks
+//!
+//! Files only grow and shrink at their end, so the tree is only ever changed along its right edge:
+//! new mappings go into the last leaf, and a full node gets a new sibling to its right.
+//! The root lives in the inode, a root that is full moves into a block of its own
+//! and becomes the first child of a new root one level higher, its new sibling the second.
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::ops::Range;
+use fs_jcb::{FsError, Result};
+
+use crate::{DeviceExt, JCBFileSystem};
+use crate::structs::{AsBuf, BlockId, DiskExtent, EXTENTS_PER_BLOCK, ExtentBlock, ExtentHeader, ExtentRoot};
+
+/// the disk block of `file_block`, if it is mapped
+pub fn lookup(fs: &JCBFileSystem, root: &ExtentRoot, file_block: usize) -> Result<Option<BlockId>> {
+    let mut node: Box<ExtentBlock>;
+    let (mut header, mut entries) = (root.header, &root.entries[..]);
+    loop {
+        let entries_in_use = in_use(&header, entries)?;
+        // the last entry that starts at or before the block
+        let extent = match entries_in_use.partition_point(|extent| extent.file_block as usize <= file_block) {
+            0 => return Ok(None),
+            i => entries_in_use[i - 1],
+        };
+        if header.depth == 0 {
+            let offset = file_block - extent.file_block as usize;
+            return Ok((offset < extent.len as usize).then_some(extent.start as usize + offset));
+        }
+        node = load(fs, extent.start as BlockId)?;
+        header = node.header;
+        entries = &node.entries;
+    }
+}
+
+/// map `file_block`, the block right after the last mapped one, to `disk_block`
+///
+/// the last extent grows if the disk block follows it as well
+pub fn append(fs: &JCBFileSystem, root: &mut ExtentRoot, file_block: usize, disk_block: BlockId) -> Result<()> {
+    let (file_block, disk_block) = (file_block as u32, disk_block as u32);
+    let sibling = match append_to(fs, &mut root.header, &mut root.entries, file_block, disk_block)? {
+        Some(sibling) => sibling,
+        None => return Ok(()),
+    };
+    // the old root moves down into a block, next to its new sibling
+    let header = ExtentHeader { max: EXTENTS_PER_BLOCK as u16, ..root.header };
+    let moved = store_new(fs, header, &root.entries)?;
+    root.header.depth += 1;
+    root.header.entries = 2;
+    root.entries[0] = DiskExtent { file_block: root.entries[0].file_block, len: 0, start: moved as u32 };
+    root.entries[1] = sibling;
+    Ok(())
+}
+
+/// drop the mappings from `blocks` on, the blocks on the disk go back to the free map
+pub fn truncate(fs: &JCBFileSystem, root: &mut ExtentRoot, blocks: usize) -> Result<()> {
+    truncate_node(fs, &mut root.header, &mut root.entries, blocks)?;
+    if root.header.entries == 0 {
+        root.header.depth = 0;
+    }
+    Ok(())
+}
+
+/// the blocks of the nodes below the root, and the extents of the leaves in file order
+///
+/// a node outside of `blocks` is never read, it makes the tree `WrongFs`
+pub fn collect(
+    fs: &JCBFileSystem,
+    root: &ExtentRoot,
+    blocks: Range<BlockId>,
+    nodes: &mut Vec<BlockId>,
+    extents: &mut Vec<DiskExtent>,
+) -> Result<()> {
+    collect_node(fs, &root.header, &root.entries, &blocks, nodes, extents)
+}
+
+fn collect_node(
+    fs: &JCBFileSystem,
+    header: &ExtentHeader,
+    entries: &[DiskExtent],
+    blocks: &Range<BlockId>,
+    nodes: &mut Vec<BlockId>,
+    extents: &mut Vec<DiskExtent>,
+) -> Result<()> {
+    let entries = in_use(header, entries)?;
+    if header.depth == 0 {
+        extents.extend_from_slice(entries);
+        return Ok(());
+    }
+    for extent in entries {
+        let id = extent.start as BlockId;
+        if !blocks.contains(&id) {
+            return Err(FsError::WrongFs);
+        }
+        nodes.push(id);
+        let child = load(fs, id)?;
+        if child.header.depth + 1 != header.depth {
+            return Err(FsError::WrongFs);
+        }
+        collect_node(fs, &child.header, &child.entries, blocks, nodes, extents)?;
+    }
+    Ok(())
+}
+
+/// the entries of a node in use, checked against its capacity
+fn in_use<'a>(header: &ExtentHeader, entries: &'a [DiskExtent]) -> Result<&'a [DiskExtent]> {
+    if header.entries > header.max || header.max as usize > entries.len() {
+        return Err(FsError::WrongFs);
+    }
+    Ok(&entries[..header.entries as usize])
+}
+
+fn load(fs: &JCBFileSystem, id: BlockId) -> Result<Box<ExtentBlock>> {
+    Ok(Box::new(fs.meta.load_struct::<ExtentBlock>(id, 0, false)?))
+}
+
+fn store(fs: &JCBFileSystem, id: BlockId, node: &ExtentBlock) -> Result<()> {
+    fs.meta.write_block(id, 0, node.as_buf())
+}
+
+/// a new node in a block of its own, holding `entries`
+fn store_new(fs: &JCBFileSystem, header: ExtentHeader, entries: &[DiskExtent]) -> Result<BlockId> {
+    let id = fs.alloc_block(None).ok_or(FsError::NoDeviceSpace)?;
+    let mut node = Box::new(ExtentBlock { header, entries: [DiskExtent::EMPTY; EXTENTS_PER_BLOCK] });
+    node.header.entries = entries.len() as u16;
+    node.entries[..entries.len()].copy_from_slice(entries);
+    if let Err(err) = store(fs, id, &node) {
+        fs.free_block(id)?;
+        return Err(err);
+    }
+    Ok(id)
+}
+
+/// append to the subtree, a node that is full gets a new sibling, which is returned
+fn append_to(
+    fs: &JCBFileSystem,
+    header: &mut ExtentHeader,
+    entries: &mut [DiskExtent],
+    file_block: u32,
+    disk_block: u32,
+) -> Result<Option<DiskExtent>> {
+    let n = in_use(header, entries)?.len();
+    let new = if header.depth == 0 {
+        if let Some(last) = entries[..n].last_mut() {
+            if last.file_block + last.len == file_block && last.start + last.len == disk_block && last.len < u32::MAX {
+                last.len += 1;
+                return Ok(None);
+            }
+        }
+        DiskExtent { file_block, len: 1, start: disk_block }
+    } else {
+        let Some(&last) = entries[..n].last() else {
+            return Err(FsError::WrongFs);
+        };
+        let mut child = load(fs, last.start as BlockId)?;
+        let sibling = append_to(fs, &mut child.header, &mut child.entries, file_block, disk_block)?;
+        store(fs, last.start as BlockId, &child)?;
+        match sibling {
+            Some(sibling) => sibling,
+            None => return Ok(None),
+        }
+    };
+    if n < header.max as usize {
+        entries[n] = new;
+        header.entries += 1;
+        return Ok(None);
+    }
+    let sibling_header = ExtentHeader { entries: 0, max: EXTENTS_PER_BLOCK as u16, depth: header.depth, reserved: 0 };
+    let sibling = store_new(fs, sibling_header, &[new])?;
+    Ok(Some(DiskExtent { file_block, len: 0, start: sibling as u32 }))
+}
+
+/// truncate the subtree, the children left empty are freed
+fn truncate_node(fs: &JCBFileSystem, header: &mut ExtentHeader, entries: &mut [DiskExtent], blocks: usize) -> Result<()> {
+    let mut keep = in_use(header, entries)?.len();
+    while keep > 0 {
+        let extent = &mut entries[keep - 1];
+        if header.depth == 0 {
+            let begin = extent.file_block as usize;
+            let end = begin + extent.len as usize;
+            for file_block in blocks.max(begin)..end {
+                fs.free_block(extent.start as usize + file_block - begin)?;
+            }
+            extent.len = (blocks.max(begin) - begin) as u32;
+            if extent.len > 0 {
+                break;
+            }
+        } else {
+            let id = extent.start as BlockId;
+            let mut child = load(fs, id)?;
+            truncate_node(fs, &mut child.header, &mut child.entries, blocks)?;
+            if child.header.entries > 0 {
+                store(fs, id, &child)?;
+                break;
+            }
+            fs.free_block(id)?;
+        }
+        keep -= 1;
+    }
+    header.entries = keep as u16;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FormatOptions;
+    use crate::structs::{EXTENT_ROOT_LEN, FEATURE_EXTENTS};
+    use crate::test::{MemDevice, clock};
+
+    #[test]
+    fn contiguous() {
+        let options = FormatOptions { features: FEATURE_EXTENTS, ..FormatOptions::default() };
+        let fs = JCBFileSystem::create_with(MemDevice::new(1 << 20), 1 << 20, options, clock()).unwrap();
+        let first = fs.alloc_block(None).unwrap();
+        let mut root = ExtentRoot::EMPTY;
+        append(&fs, &mut root, 0, first).unwrap();
+        for i in 1..100 {
+            let id = fs.alloc_block(Some(first + i)).unwrap();
+            assert_eq!(id, first + i);
+            append(&fs, &mut root, i, id).unwrap();
+        }
+        assert_eq!(root.header.entries, 1);
+        assert_eq!(root.entries[0], DiskExtent { file_block: 0, len: 100, start: first as u32 });
+        assert_eq!(lookup(&fs, &root, 42), Ok(Some(first + 42)));
+        assert_eq!(lookup(&fs, &root, 100), Ok(None));
+
+        let before = fs.stats().free_blocks;
+        truncate(&fs, &mut root, 30).unwrap();
+        assert_eq!(root.entries[0].len, 30);
+        assert_eq!(fs.stats().free_blocks, before + 70);
+        assert_eq!(lookup(&fs, &root, 30), Ok(None));
+    }
+
+    #[test]
+    fn deep() {
+        let fs = JCBFileSystem::create(MemDevice::new(16 << 20), 16 << 20, clock()).unwrap();
+        // every other block, so that no two blocks make an extent
+        let blocks: Vec<BlockId> = (0..2 * (EXTENT_ROOT_LEN * EXTENTS_PER_BLOCK + 10))
+            .map(|_| fs.alloc_block(None).unwrap())
+            .step_by(2)
+            .collect();
+        let before = fs.stats().free_blocks;
+
+        let mut root = ExtentRoot::EMPTY;
+        for (i, &id) in blocks.iter().enumerate() {
+            append(&fs, &mut root, i, id).unwrap();
+        }
+        // the root is full of index nodes, and they point to more index nodes
+        assert_eq!(root.header.depth, 2);
+        for (i, &id) in blocks.iter().enumerate() {
+            assert_eq!(lookup(&fs, &root, i), Ok(Some(id)), "{}", i);
+        }
+        assert_eq!(lookup(&fs, &root, blocks.len()), Ok(None));
+        // the first leaf is the old root, a leaf for every EXTENTS_PER_BLOCK extents after it,
+        // and 2 index nodes above them
+        let nodes = 1 + (blocks.len() - EXTENT_ROOT_LEN).div_ceil(EXTENTS_PER_BLOCK) + 2;
+        assert_eq!(before - fs.stats().free_blocks, nodes);
+
+        truncate(&fs, &mut root, EXTENTS_PER_BLOCK + 1).unwrap();
+        assert_eq!(lookup(&fs, &root, EXTENTS_PER_BLOCK), Ok(Some(blocks[EXTENTS_PER_BLOCK])));
+        assert_eq!(lookup(&fs, &root, EXTENTS_PER_BLOCK + 1), Ok(None));
+        // growing again after the cut goes on where it stopped
+        let id = fs.alloc_block(Some(blocks[EXTENTS_PER_BLOCK + 1])).unwrap();
+        assert_eq!(id, blocks[EXTENTS_PER_BLOCK + 1]);
+        append(&fs, &mut root, EXTENTS_PER_BLOCK + 1, id).unwrap();
+        assert_eq!(lookup(&fs, &root, EXTENTS_PER_BLOCK + 1), Ok(Some(blocks[EXTENTS_PER_BLOCK + 1])));
+
+        truncate(&fs, &mut root, 0).unwrap();
+        assert_eq!(root.header, ExtentRoot::EMPTY.header);
+        // every node is gone, and so are the blocks mapped
+        assert_eq!(fs.stats().free_blocks, before + blocks.len());
+    }
+}
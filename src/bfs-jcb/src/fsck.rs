@@ -0,0 +1,566 @@
+//! Offline check of an image
+//!
+//! Everything reachable from the root dir is walked. Every block and inode slot the walk finds in
+//! use is cross-checked against the free map and the inode map, and the links of every inode
+//! against the entries naming it. Inodes in use but out of reach are orphans.
+//!
+//! `check` with `repair` rebuilds the maps and the link counts from what the walk found, and links
+//! the orphans into `/lost+found`. It only does so when every problem found can be repaired,
+//! an image with a broken block map or a broken dir is left as it is.
+//!
+//! The fs must have just been opened, nothing else may use it while it is checked.
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use fs_jcb::{FileSystem, FileType, FsError, Inode, Result};
+
+use crate::{DeviceExt, JCBFileSystem, Layout, extent};
+use crate::structs::{
+    AsBuf, BLK_NENTRY, BLKSIZE, BlockId, DIRENT_SIZE, DiskEntry, DiskExtent, DiskINode, ENTRY_SIZE, FEATURE_CHECKSUMS,
+    FEATURE_JOURNAL, INODE_CRITICAL, INODE_EXTENTS, INO_ROOT, InodeId, MAX_NBLOCK_DIRECT, MAX_NBLOCK_DOUBLE_INDIRECT, MAX_NBLOCK_INDIRECT, Str256,
+};
+
+/// the dir orphans are linked into
+pub const LOST_AND_FOUND: &str = "lost+found";
+
+/// an inconsistency found by `check`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Problem {
+    /// the slot of the inode holds no valid inode, or it does not match its checksum
+    BadInode { inode: InodeId },
+    /// a block of a dir or a critical file does not match its checksum
+    BadChecksum { inode: InodeId, block: BlockId },
+    /// a block of the inode is outside of the data area, or the block map is broken
+    BadBlockMap { inode: InodeId },
+    /// the size does not take as many blocks as the inode has
+    BadSize { inode: InodeId },
+    /// the block is taken by two inodes
+    DuplicateBlock { block: BlockId, inode: InodeId, other: InodeId },
+    /// a block in use is free in the free map
+    UsedBlockFree { block: BlockId },
+    /// a block nobody uses is not free in the free map
+    LeakedBlock { block: BlockId },
+    /// an inode in use is free in the inode map
+    UsedInodeFree { inode: InodeId },
+    /// an inode out of reach with no link left
+    LeakedInode { inode: InodeId },
+    /// an inode out of reach, still linked
+    Orphan { inode: InodeId },
+    /// `nlinks` is not the number of entries naming the inode
+    WrongLinks { inode: InodeId, nlinks: u16, entries: usize },
+    /// the size of the dir is not a whole number of entries
+    BadDirSize { dir: InodeId, size: u64 },
+    /// the first entry of the dir is not "." to itself
+    BadDot { dir: InodeId },
+    /// the second entry of the dir is not ".." to its parent
+    BadDotDot { dir: InodeId, parent: InodeId },
+    /// the entry names no inode of the table
+    BadEntry { dir: InodeId, slot: usize },
+    /// the dir has an entry besides the one in its parent
+    DirLinkedTwice { dir: InodeId, inode: InodeId },
+}
+
+impl Problem {
+    /// `check` with `repair` can fix it
+    pub fn repairable(&self) -> bool {
+        matches!(
+            self,
+            Problem::UsedBlockFree { .. }
+                | Problem::LeakedBlock { .. }
+                | Problem::UsedInodeFree { .. }
+                | Problem::LeakedInode { .. }
+                | Problem::Orphan { .. }
+                | Problem::WrongLinks { .. }
+        )
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Problem::BadInode { inode } => write!(f, "inode {}: not a valid inode", inode),
+            Problem::BadChecksum { inode, block } => {
+                write!(f, "inode {}: block {} does not match its checksum", inode, block)
+            }
+            Problem::BadBlockMap { inode } => write!(f, "inode {}: broken block map", inode),
+            Problem::BadSize { inode } => write!(f, "inode {}: size does not match the blocks", inode),
+            Problem::DuplicateBlock { block, inode, other } => {
+                write!(f, "block {}: used by inode {} and inode {}", block, inode, other)
+            }
+            Problem::UsedBlockFree { block } => write!(f, "block {}: in use but free in the free map", block),
+            Problem::LeakedBlock { block } => write!(f, "block {}: unused but not free in the free map", block),
+            Problem::UsedInodeFree { inode } => write!(f, "inode {}: in use but free in the inode map", inode),
+            Problem::LeakedInode { inode } => write!(f, "inode {}: unlinked but not free in the inode map", inode),
+            Problem::Orphan { inode } => write!(f, "inode {}: linked but not reachable", inode),
+            Problem::WrongLinks { inode, nlinks, entries } => {
+                write!(f, "inode {}: {} links, but {} entries", inode, nlinks, entries)
+            }
+            Problem::BadDirSize { dir, size } => write!(f, "dir {}: size {} is not a whole number of entries", dir, size),
+            Problem::BadDot { dir } => write!(f, "dir {}: bad \".\" entry", dir),
+            Problem::BadDotDot { dir, parent } => write!(f, "dir {}: \"..\" is not the parent {}", dir, parent),
+            Problem::BadEntry { dir, slot } => write!(f, "dir {}: entry {} names no inode", dir, slot),
+            Problem::DirLinkedTwice { dir, inode } => write!(f, "dir {}: links dir {} a second time", dir, inode),
+        }
+    }
+}
+
+/// check the image, and repair it if asked and possible, the problems found are returned
+///
+/// the problems are the ones found before the repair, check again to see the result
+pub fn check(fs: &JCBFileSystem, repair: bool) -> Result<Vec<Problem>> {
+    let (blocks, inodes, layout) = {
+        let super_block = fs.super_block.read();
+        let (blocks, inodes) = (super_block.blocks as usize, super_block.inodes as usize);
+        let journal_blocks = match super_block.features & FEATURE_JOURNAL {
+            0 => 0,
+            _ => super_block.journal_blocks as usize,
+        };
+        let checksums = super_block.features & FEATURE_CHECKSUMS != 0;
+        (blocks, inodes, Layout::new(blocks, inodes, journal_blocks, checksums))
+    };
+    let mut checker = Checker {
+        fs,
+        data: layout.data..blocks,
+        owner: vec![0; blocks],
+        entries: vec![0; inodes],
+        nlinks: vec![None; inodes],
+        orphans: BTreeMap::new(),
+        problems: Vec::new(),
+    };
+    checker.walk()?;
+    let problems = checker.problems.clone();
+    if repair && !problems.is_empty() && problems.iter().all(Problem::repairable) {
+        checker.repair()?;
+    }
+    Ok(problems)
+}
+
+struct Checker<'a> {
+    fs: &'a JCBFileSystem,
+    /// the data area
+    data: Range<BlockId>,
+    /// the inode using each block, 0 if none
+    owner: Vec<InodeId>,
+    /// the number of entries naming each inode
+    entries: Vec<usize>,
+    /// the links of each inode reached, as found on the disk
+    nlinks: Vec<Option<u16>>,
+    /// the orphans no other orphan links to, and the inode the ".." of an orphan dir names
+    orphans: BTreeMap<InodeId, Option<InodeId>>,
+    problems: Vec<Problem>,
+}
+
+impl Checker<'_> {
+    fn walk(&mut self) -> Result<()> {
+        if let Some(root) = self.reach(INO_ROOT)? {
+            if root.type_ != FileType::Dir {
+                self.problems.push(Problem::BadInode { inode: INO_ROOT });
+            } else {
+                self.walk_dir(INO_ROOT, Some(INO_ROOT))?;
+            }
+        }
+
+        let inode_map = self.fs.inode_map.read().clone();
+        for id in INO_ROOT + 1..self.nlinks.len() {
+            if inode_map[id] || self.nlinks[id].is_some() {
+                continue;
+            }
+            match self.load(id)? {
+                None => self.problems.push(Problem::BadInode { inode: id }),
+                Some(disk_inode) if disk_inode.nlinks == 0 => self.problems.push(Problem::LeakedInode { inode: id }),
+                Some(_) => {
+                    self.orphans.insert(id, None);
+                    if let Some(disk_inode) = self.reach(id)? {
+                        if disk_inode.type_ == FileType::Dir {
+                            self.walk_dir(id, None)?;
+                        }
+                    }
+                }
+            }
+        }
+
+        self.check_free_map();
+        for id in INO_ROOT..self.nlinks.len() {
+            let Some(nlinks) = self.nlinks[id] else { continue };
+            if self.orphans.contains_key(&id) {
+                self.problems.push(Problem::Orphan { inode: id });
+            }
+            let entries = self.expected_links(id);
+            if nlinks as usize != entries {
+                self.problems.push(Problem::WrongLinks { inode: id, nlinks, entries });
+            }
+        }
+        Ok(())
+    }
+
+    /// the links the inode should have, an orphan gets an entry in lost+found
+    fn expected_links(&self, id: InodeId) -> usize {
+        self.entries[id] + self.orphans.contains_key(&id) as usize
+    }
+
+    /// walk the tree below `dir`, an orphan has no parent to check its ".." against
+    fn walk_dir(&mut self, dir: InodeId, parent: Option<InodeId>) -> Result<()> {
+        let mut stack = vec![(dir, parent)];
+        while let Some((dir, parent)) = stack.pop() {
+            let Some(entries) = self.read_dir(dir)? else { continue };
+            for (slot, entry) in entries.iter().enumerate() {
+                let id = entry.inode_id as InodeId;
+                match slot {
+                    0 if !(entry.name.is(".") && id == dir) => self.problems.push(Problem::BadDot { dir }),
+                    1 => match parent {
+                        Some(parent) if !(entry.name.is("..") && id == parent) => {
+                            self.problems.push(Problem::BadDotDot { dir, parent });
+                        }
+                        // rewritten when the orphan is linked into lost+found, unless another orphan
+                        // links to it first
+                        None => {
+                            if entry.name.is("..") && id < self.nlinks.len() {
+                                self.orphans.insert(dir, Some(id));
+                            }
+                            continue;
+                        }
+                        _ => {}
+                    },
+                    _ => {}
+                }
+                if entry.is_free() {
+                    continue;
+                }
+                if id >= self.nlinks.len() {
+                    self.problems.push(Problem::BadEntry { dir, slot });
+                    continue;
+                }
+                self.entries[id] += 1;
+                if slot < 2 {
+                    continue;
+                }
+                if self.nlinks[id].is_some() {
+                    // an orphan found by an orphan walked later is no orphan of its own
+                    match self.orphans.remove(&id) {
+                        Some(Some(dotdot)) => {
+                            self.entries[dotdot] += 1;
+                            if dotdot != dir {
+                                self.problems.push(Problem::BadDotDot { dir: id, parent: dir });
+                            }
+                        }
+                        Some(None) => {}
+                        None if self.is_dir(id)? => self.problems.push(Problem::DirLinkedTwice { dir, inode: id }),
+                        None => {}
+                    }
+                    continue;
+                }
+                if let Some(disk_inode) = self.reach(id)? {
+                    if disk_inode.type_ == FileType::Dir {
+                        stack.push((id, Some(dir)));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn is_dir(&self, id: InodeId) -> Result<bool> {
+        Ok(self.load(id)?.is_some_and(|disk_inode| disk_inode.type_ == FileType::Dir))
+    }
+
+    /// the inode is in use, its blocks are taken
+    fn reach(&mut self, id: InodeId) -> Result<Option<DiskINode>> {
+        let Some(disk_inode) = self.load(id)? else {
+            self.problems.push(Problem::BadInode { inode: id });
+            return Ok(None);
+        };
+        self.nlinks[id] = Some(disk_inode.nlinks);
+        if self.fs.inode_map.read()[id] {
+            self.problems.push(Problem::UsedInodeFree { inode: id });
+        }
+        if disk_inode.size.div_ceil(BLKSIZE as u64) != disk_inode.blocks as u64 {
+            self.problems.push(Problem::BadSize { inode: id });
+        }
+        match self.block_map(&disk_inode)? {
+            Some((data, meta)) => {
+                if self.fs.checksums() && (disk_inode.type_ == FileType::Dir || disk_inode.flags & INODE_CRITICAL != 0) {
+                    let mut buf = vec![0u8; BLKSIZE];
+                    for &block in &data {
+                        match self.fs.read_checked_block(block, &mut buf) {
+                            Err(FsError::Corrupted) => self.problems.push(Problem::BadChecksum { inode: id, block }),
+                            result => result?,
+                        }
+                    }
+                }
+                for block in data.into_iter().chain(meta) {
+                    match self.owner[block] {
+                        0 => self.owner[block] = id,
+                        other => self.problems.push(Problem::DuplicateBlock { block, inode: other, other: id }),
+                    }
+                }
+            }
+            None => self.problems.push(Problem::BadBlockMap { inode: id }),
+        }
+        Ok(Some(disk_inode))
+    }
+
+    /// the disk inode, if the type is one at all and it matches its checksum
+    fn load(&self, id: InodeId) -> Result<Option<DiskINode>> {
+        let (block, offset) = self.fs.inode_slot(id);
+        match self.fs.meta.load_struct(block, offset, self.fs.checksums()) {
+            Ok(disk_inode) => Ok(Some(disk_inode)),
+            Err(FsError::WrongFs | FsError::Corrupted) => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// the data blocks in file order and the blocks mapping them, `None` if the map is broken
+    fn block_map(&self, disk_inode: &DiskINode) -> Result<Option<(Vec<BlockId>, Vec<BlockId>)>> {
+        let blocks = disk_inode.blocks as usize;
+        let (mut data, mut meta) = (Vec::new(), Vec::new());
+        if disk_inode.flags & INODE_EXTENTS != 0 {
+            let mut extents = Vec::new();
+            match extent::collect(self.fs, &disk_inode.extents, self.data.clone(), &mut meta, &mut extents) {
+                Err(FsError::WrongFs) => return Ok(None),
+                result => result?,
+            }
+            for &DiskExtent { file_block, len, start } in &extents {
+                let run = start as BlockId..start as BlockId + len as BlockId;
+                if file_block as usize != data.len() || len == 0 || run.end > self.data.end || run.start < self.data.start {
+                    return Ok(None);
+                }
+                data.extend(run);
+            }
+        } else {
+            if blocks > MAX_NBLOCK_DOUBLE_INDIRECT {
+                return Ok(None);
+            }
+            data.extend(disk_inode.direct.iter().take(blocks).map(|&id| id as BlockId));
+            if blocks > MAX_NBLOCK_DIRECT {
+                let Some(entries) = self.read_entries(disk_inode.indirect as BlockId)? else { return Ok(None) };
+                meta.push(disk_inode.indirect as BlockId);
+                data.extend(entries.into_iter().take(blocks - MAX_NBLOCK_DIRECT));
+            }
+            if blocks > MAX_NBLOCK_INDIRECT {
+                let Some(indirects) = self.read_entries(disk_inode.db_indirect as BlockId)? else { return Ok(None) };
+                meta.push(disk_inode.db_indirect as BlockId);
+                let left = blocks - MAX_NBLOCK_INDIRECT;
+                for (i, indirect) in indirects.into_iter().take(left.div_ceil(BLK_NENTRY)).enumerate() {
+                    let Some(entries) = self.read_entries(indirect)? else { return Ok(None) };
+                    meta.push(indirect);
+                    data.extend(entries.into_iter().take(left - i * BLK_NENTRY));
+                }
+            }
+        }
+        if data.len() != blocks || !data.iter().all(|id| self.data.contains(id)) {
+            return Ok(None);
+        }
+        Ok(Some((data, meta)))
+    }
+
+    /// the block pointers of an indirect block, if it is in the data area
+    fn read_entries(&self, id: BlockId) -> Result<Option<Vec<BlockId>>> {
+        if !self.data.contains(&id) {
+            return Ok(None);
+        }
+        let mut raw = vec![0u8; BLKSIZE];
+        self.fs.meta.read_block(id, 0, &mut raw)?;
+        let entries = raw.chunks_exact(ENTRY_SIZE).map(|entry| u32::from_ne_bytes(entry.try_into().unwrap()) as BlockId);
+        Ok(Some(entries.collect()))
+    }
+
+    /// the entries of the dir, `None` if they cannot be trusted
+    fn read_dir(&mut self, dir: InodeId) -> Result<Option<Vec<DiskEntry>>> {
+        let Some(disk_inode) = self.load(dir)? else { return Ok(None) };
+        let size = disk_inode.size;
+        if !(size as usize).is_multiple_of(DIRENT_SIZE) || (size as usize) < 2 * DIRENT_SIZE {
+            self.problems.push(Problem::BadDirSize { dir, size });
+            return Ok(None);
+        }
+        let Some((blocks, _)) = self.block_map(&disk_inode)? else { return Ok(None) };
+        let mut raw = vec![0u8; blocks.len() * BLKSIZE];
+        for (block, buf) in blocks.iter().zip(raw.chunks_exact_mut(BLKSIZE)) {
+            self.fs.meta.read_block(*block, 0, buf)?;
+        }
+        let entries = raw[..size as usize].chunks_exact(DIRENT_SIZE).map(|raw| {
+            let mut entry = DiskEntry::free();
+            entry.as_buf_mut().copy_from_slice(raw);
+            entry
+        });
+        Ok(Some(entries.collect()))
+    }
+
+    fn check_free_map(&mut self) {
+        let free_map = self.fs.free_map.read();
+        for block in self.data.clone() {
+            match (self.owner[block] != 0, free_map[block]) {
+                (true, true) => self.problems.push(Problem::UsedBlockFree { block }),
+                (false, false) => self.problems.push(Problem::LeakedBlock { block }),
+                _ => {}
+            }
+        }
+    }
+
+    /// make the image what the walk found
+    fn repair(&self) -> Result<()> {
+        {
+            let mut super_block = self.fs.super_block.write();
+            let mut free_map = self.fs.free_map.write();
+            for block in self.data.clone() {
+                free_map.set(block, self.owner[block] == 0);
+            }
+            super_block.unused_blocks = free_map.count_ones() as u32;
+            let mut inode_map = self.fs.inode_map.write();
+            for id in INO_ROOT + 1..self.nlinks.len() {
+                inode_map.set(id, self.nlinks[id].is_none());
+            }
+            super_block.unused_inodes = inode_map.count_ones() as u32;
+        }
+
+        for (id, nlinks) in self.nlinks.iter().enumerate() {
+            let Some(nlinks) = *nlinks else { continue };
+            let links = self.expected_links(id);
+            if nlinks as usize != links {
+                // a repair too large for a transaction is committed in steps, any of them leaves an image to repair
+                self.fs.reserve(1)?;
+                let mut disk_inode = self.load(id)?.ok_or(FsError::WrongFs)?;
+                disk_inode.nlinks = links as u16;
+                self.fs.write_disk_inode(id, &disk_inode)?;
+            }
+        }
+
+        if !self.orphans.is_empty() {
+            let root = self.fs.get_inode(INO_ROOT)?;
+            let lost_and_found = match root.find(LOST_AND_FOUND) {
+                Ok(inode) => inode,
+                Err(FsError::EntryNotFound) => root.create(LOST_AND_FOUND, FileType::Dir, 0o700)?,
+                Err(err) => return Err(err),
+            };
+            let lost_and_found_id = lost_and_found.metadata()?.inode_id;
+            let lost_and_found = self.fs.get_inode(lost_and_found_id)?;
+            if lost_and_found.disk_inode.read().type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            for &id in self.orphans.keys() {
+                // the entry, and ".." of a dir
+                self.fs.reserve(lost_and_found.entry_blocks() + 2)?;
+                let orphan = self.fs.get_inode(id)?;
+                let name = format!("#{}", id);
+                lost_and_found.append_dir_entry(&DiskEntry { inode_id: id as u32, name: Str256::from(&*name) })?;
+                if orphan.disk_inode.read().type_ == FileType::Dir {
+                    orphan.write_dir_entry(1, &DiskEntry { inode_id: lost_and_found_id as u32, name: Str256::from("..") })?;
+                    lost_and_found.disk_inode.write().nlinks += 1;
+                }
+            }
+        }
+        self.fs.sync()
+    }
+}
+
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::sync::Arc;
+    use crate::FormatOptions;
+    use crate::structs::FEATURE_EXTENTS;
+    use crate::test::{MemDevice, clock};
+
+    /// a dir with a file in it, the file linked twice and critical if it can be, and a symlink
+    fn image(options: FormatOptions) -> Arc<MemDevice> {
+        let device = MemDevice::new(4 << 20);
+        let fs = JCBFileSystem::create_with(device.clone(), 4 << 20, options, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let dir = root.create("a", FileType::Dir, 0o755).unwrap();
+        let file = dir.create("f", FileType::File, 0o644).unwrap();
+        file.write_at(3 * BLKSIZE, b"end").unwrap();
+        match fs.mark_critical(&file) {
+            Ok(()) | Err(FsError::NotSupported) => {}
+            Err(err) => panic!("{:?}", err),
+        }
+        dir.link("g", &file).unwrap();
+        root.create_symlink("l", "a/f").unwrap();
+        drop((file, dir, root, fs));
+        device
+    }
+
+    #[test]
+    fn clean() {
+        let journal = FormatOptions { journal_blocks: 64, ..FormatOptions::default() };
+        let extents = FormatOptions { features: FEATURE_EXTENTS, ..journal };
+        let checksums = FormatOptions { features: FEATURE_EXTENTS | FEATURE_CHECKSUMS, ..journal };
+        for options in [FormatOptions::default(), journal, extents, checksums] {
+            let fs = JCBFileSystem::open(image(options), clock()).unwrap();
+            assert_eq!(check(&fs, false), Ok(Vec::new()));
+        }
+    }
+
+    #[test]
+    fn repair() {
+        let device = image(FormatOptions::default());
+        let fs = JCBFileSystem::open(device.clone(), clock()).unwrap();
+        let root = fs.get_inode(INO_ROOT).unwrap();
+        let dir = root.find("a").unwrap().metadata().unwrap().inode_id;
+        // the entry of the dir is lost, and so is a block
+        root.write_dir_entry(2, &DiskEntry::free()).unwrap();
+        let block = fs.alloc_block(None).unwrap();
+        drop((root, fs));
+
+        let fs = JCBFileSystem::open(device.clone(), clock()).unwrap();
+        let problems = vec![
+            Problem::LeakedBlock { block },
+            // the ".." of the dir links the root
+            Problem::WrongLinks { inode: INO_ROOT, nlinks: 3, entries: 2 },
+            Problem::Orphan { inode: dir },
+        ];
+        assert_eq!(check(&fs, false), Ok(problems.clone()));
+        assert_eq!(check(&fs, true), Ok(problems));
+        drop(fs);
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        assert_eq!(check(&fs, false), Ok(Vec::new()));
+        let root = fs.root_inode().unwrap();
+        let file = root.find_by_path(&fs_jcb::Credentials::root(), &format!("{}/#{}/g", LOST_AND_FOUND, dir)).unwrap();
+        let mut buf = [0; 3];
+        assert_eq!(file.read_at(3 * BLKSIZE, &mut buf), Ok(3));
+        assert_eq!(&buf, b"end");
+        assert_eq!(root.find(LOST_AND_FOUND).unwrap().find("..").unwrap().metadata().unwrap().inode_id, INO_ROOT);
+    }
+
+    #[test]
+    fn unrepairable() {
+        let device = image(FormatOptions::default());
+        let fs = JCBFileSystem::open(device.clone(), clock()).unwrap();
+        let root = fs.get_inode(INO_ROOT).unwrap();
+        let file = root.find("a").unwrap().find("f").unwrap().metadata().unwrap().inode_id;
+        let symlink = root.find("l").unwrap().metadata().unwrap().inode_id;
+        // the symlink takes the first block of the file
+        let (first, own) = {
+            let file = fs.get_inode(file).unwrap();
+            let symlink = fs.get_inode(symlink).unwrap();
+            let first = file.disk_inode.read().direct[0];
+            let own = core::mem::replace(&mut symlink.disk_inode.write().direct[0], first);
+            (first as BlockId, own as BlockId)
+        };
+        drop((root, fs));
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let problems = check(&fs, true).unwrap();
+        assert!(problems.contains(&Problem::DuplicateBlock { block: first, inode: symlink, other: file }));
+        assert!(problems.contains(&Problem::LeakedBlock { block: own }));
+        // nothing is repaired while something cannot be
+        assert_eq!(check(&fs, false), Ok(problems));
+    }
+
+    #[test]
+    fn checksums() {
+        let device = image(FormatOptions { features: FEATURE_CHECKSUMS, ..FormatOptions::default() });
+        let fs = JCBFileSystem::open(device.clone(), clock()).unwrap();
+        let file = fs.root_inode().unwrap().find("a").unwrap().find("f").unwrap().metadata().unwrap().inode_id;
+        let block = fs.get_inode(file).unwrap().disk_inode.read().direct[1] as BlockId;
+        drop(fs);
+
+        device.0.lock()[block * BLKSIZE] ^= 1;
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        assert_eq!(check(&fs, true), Ok(vec![Problem::BadChecksum { inode: file, block }]));
+        assert_eq!(check(&fs, false), Ok(vec![Problem::BadChecksum { inode: file, block }]));
+    }
+}
@@ -1,19 +1,20 @@
-use std::collections::BTreeMap;
-use std::fs::FileType;
-use std::sync::Arc;
-// TODO FIXME
-use fs_jcb::{FileSystem, FileType, MetaData, Result, Device, Dirty, BlockRange, BlockIter, BlockDevice, uninit_memory};
+use fs_jcb::{DirEntry, FileSystem, FileType, FsError, Inode, MetaData, Result, Device, Dirty, BlockRange, BlockIter, uninit_memory};
 
 use alloc::collections::BTreeMap;
 use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
 use alloc::sync::Arc;
+use core::any::Any;
 use spin::RwLock;
 
 
-use crate::bfs::{JCBFileSystem};
-use crate::{DeviceExt, Inode, JCBFileSystem, vfs};
-use crate::bfs::structs::{AsBuf, DIRENT_SIZE, DiskEntry, DiskINode, InodeId, Str256};
-use crate::structs::{BLKSIZE_LOG2, BlockId, DIRENT_SIZE, DiskEntry, DiskINode, InodeId, Str256};
+use crate::{DeviceExt, JCBFileSystem, extent};
+use crate::crc::crc32c;
+use crate::structs::{AsBuf, BLK_NENTRY, BLKSIZE, BLKSIZE_LOG2, BlockId, CHECKSUMS_PER_BLOCK, DIRENT_BATCH, DIRENT_SIZE, DiskEntry, DiskINode, ENTRY_SIZE, EXTENTS_PER_BLOCK, FEATURE_EXTENTS, INODE_CRITICAL, INODE_EXTENTS, InodeId, MAX_EXTENT_FILE_SIZE, MAX_FILE_SIZE, MAX_FNAME_LEN, MODE_MASK, MAX_NBLOCK_DIRECT, MAX_NBLOCK_DOUBLE_INDIRECT, MAX_NBLOCK_INDIRECT, Str256, ZERO_BLOCK_CHECKSUM};
+
+/// blocks going to the journal that a file grows by or is written in at once
+const JOURNAL_STEP: usize = 8;
 
 
 
@@ -21,9 +22,9 @@ pub struct InodeImpl{
     /// INode number
     id: InodeId,
     /// On-disk INode
-    disk_inode: RwLock<Dirty<DiskINode>>,
+    pub(crate) disk_inode: RwLock<Dirty<DiskINode>>,
     /// Reference to SFS, used by almost all operations
-    fs: Arc<JCBFileSystem>,
+    pub(crate) fs: Arc<JCBFileSystem>,
     /// Char/block device id (major, minor)
     /// e.g. crw-rw-rw- 1 root wheel 3, 2 May 13 16:40 /dev/null
     device_inode_id: usize,
@@ -32,88 +33,392 @@ pub struct InodeImpl{
 }
 impl Inode for InodeImpl{
     fn metadata(&self)->Result<MetaData>{
-        let inode=self.disk_inode.read();
+        let disk_inode=self.disk_inode.read();
+        let rdev=match disk_inode.type_ {
+            FileType::CharDevice | FileType::BlockDevice => disk_inode.device_inode_id,
+            _ => 0,
+        };
         let meta_data=MetaData{
-            size: 0,
-            blk_size: 0,
-            blocks: 0,
-            atime: Timespec {},
-            mtime: Timespec {},
-            ctime: Timespec {},
+            size: disk_inode.size as usize,
+            blk_size: BLKSIZE,
+            blocks: disk_inode.blocks as usize,
+            atime: disk_inode.atime.into(),
+            mtime: disk_inode.mtime.into(),
+            ctime: disk_inode.ctime.into(),
             dev: 0,
-            inode_id: 0,
-            type_: FileType::File,
-            permission: 0,
-            uid: 0,
-            gid: 0,
-            rdev: 0,
+            inode_id: self.id,
+            type_: disk_inode.type_,
+            permission: disk_inode.mode as u16,
+            uid: disk_inode.uid as usize,
+            gid: disk_inode.gid as usize,
+            rdev,
         };
         Ok(meta_data)
     }
 
-    fn set_metadata(&self)->Result<()>{
-        let inode=self.disk_inode.write();
-        
-
+    fn set_metadata(&self,metadata:&MetaData)->Result<()>{
+        self.fs.reserve(1)?;
+        let now=self.fs.current_time();
+        let mut disk_inode=self.disk_inode.write();
+        disk_inode.mode=metadata.permission as u32&MODE_MASK;
+        disk_inode.uid=u32::try_from(metadata.uid).map_err(|_| FsError::InvalidParam)?;
+        disk_inode.gid=u32::try_from(metadata.gid).map_err(|_| FsError::InvalidParam)?;
+        disk_inode.atime=metadata.atime.into();
+        disk_inode.mtime=metadata.mtime.into();
+        disk_inode.ctime=now.into();
+        Ok(())
     }
 
     fn create(&self, name: &str, type_: FileType,mode: u32) -> Result<Arc<dyn Inode>> {
-        let mut inode=match type_ {
-            vfs::FileType::File => self.fs.new_inode_file()?,
+        self.check_new_entry(name)?;
+        self.fs.reserve(self.entry_blocks()+self.new_inode_blocks(DiskINode::new_dir(),1))?;
+        let inode=match type_ {
+            FileType::File => self.fs.new_inode_file()?,
 
-            vfs::FileType::Dir => self.fs.new_inode_dir(self.id)?,
+            FileType::Dir => self.fs.new_inode_dir(self.id)?,
 
-            _ => return Err(vfs::FsError::InvalidParam),
+            _ => return Err(FsError::InvalidParam),
         };
+        inode.disk_inode.write().mode=mode&MODE_MASK;
 
-        self._append_dir_entry(&DiskEntry{
-            inode_id:inode.id ,
+        // a new inode with no link is released when it is dropped, so a failure here leaks nothing
+        self.append_dir_entry(&DiskEntry{
+            inode_id:inode.id as u32,
             name: Str256::from(name)
-        });
+        })?;
+        inode.disk_inode.write().nlinks+=1;
+        if type_==FileType::Dir {
+            // "." of the new dir and its ".." to us
+            inode.disk_inode.write().nlinks+=1;
+            self.disk_inode.write().nlinks+=1;
+        }
+        self.modified();
 
         Ok(inode)
     }
+
+    fn mknod(&self, name: &str, type_: FileType, mode: u32, rdev: usize) -> Result<Arc<dyn Inode>> {
+        self.check_new_entry(name)?;
+        self.fs.reserve(self.entry_blocks()+self.new_inode_blocks(DiskINode::new_file(),0))?;
+        let inode=self.fs.new_inode_device(type_,rdev)?;
+        inode.disk_inode.write().mode=mode&MODE_MASK;
+        self.append_dir_entry(&DiskEntry{
+            inode_id: inode.id as u32,
+            name: Str256::from(name),
+        })?;
+        inode.disk_inode.write().nlinks+=1;
+        self.modified();
+        Ok(inode)
+    }
+
+    fn create_symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>> {
+        self.check_new_entry(name)?;
+        if target.is_empty() {
+            return Err(FsError::InvalidParam);
+        }
+        self.fs.reserve(self.entry_blocks()+self.new_inode_blocks(DiskINode::new_symlink(),target.len().div_ceil(BLKSIZE)))?;
+        let inode=self.fs.new_inode_symlink()?;
+        inode._resize(target.len())?;
+        inode._write_at(0,target.as_bytes())?;
+        self.append_dir_entry(&DiskEntry{
+            inode_id: inode.id as u32,
+            name: Str256::from(name),
+        })?;
+        inode.disk_inode.write().nlinks+=1;
+        self.modified();
+        Ok(inode)
+    }
+
+    fn read_link(&self) -> Result<String> {
+        let size={
+            let disk_inode=self.disk_inode.read();
+            if disk_inode.type_!=FileType::SymLink {
+                return Err(FsError::InvalidParam);
+            }
+            disk_inode.size as usize
+        };
+        self.fs.reserve(1)?;
+        let mut buf=vec![0u8;size];
+        self._read_at(0,&mut buf)?;
+        self.accessed();
+        String::from_utf8(buf).map_err(|_| FsError::InvalidParam)
+    }
+
+    fn link(&self, name: &str, other: &Arc<dyn Inode>) -> Result<()> {
+        self.check_new_entry(name)?;
+        let child=other.downcast_ref::<InodeImpl>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs,&child.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        {
+            let disk_inode=child.disk_inode.read();
+            if disk_inode.type_==FileType::Dir {
+                return Err(FsError::IsDir);
+            }
+            // unlinked but still open, its blocks go with the last handle
+            if disk_inode.nlinks==0 {
+                return Err(FsError::EntryNotFound);
+            }
+            if disk_inode.nlinks==u16::MAX {
+                return Err(FsError::TooManyLinks);
+            }
+        }
+        self.fs.reserve(self.entry_blocks()+1)?;
+        self.append_dir_entry(&DiskEntry{
+            inode_id: child.id as u32,
+            name: Str256::from(name),
+        })?;
+        child.disk_inode.write().nlinks+=1;
+        child.changed();
+        self.modified();
+        Ok(())
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn Inode>, new_name: &str) -> Result<()> {
+        self.check_dir_alive()?;
+        if old_name=="." || old_name==".." {
+            return Err(FsError::IsDir);
+        }
+        let dest=target.downcast_ref::<InodeImpl>().ok_or(FsError::NotSameFs)?;
+        if !Arc::ptr_eq(&self.fs,&dest.fs) {
+            return Err(FsError::NotSameFs);
+        }
+        dest.check_dir_alive()?;
+        check_name(new_name)?;
+        // ".." of a dir moving, and the slots of the inode and the one it replaces
+        self.fs.reserve(self.entry_blocks()+dest.entry_blocks()+4)?;
+
+        let (inode_id,entry_id)=self.get_entry_and_inode_id(old_name)?.ok_or(FsError::EntryNotFound)?;
+        let inode=self.fs.get_inode(inode_id)?;
+        let is_dir=inode.disk_inode.read().type_==FileType::Dir;
+        if is_dir && dest.is_descendant_of(inode_id)? {
+            return Err(FsError::InvalidParam);
+        }
+
+        let new_entry=DiskEntry{
+            inode_id: inode_id as u32,
+            name: Str256::from(new_name),
+        };
+        match dest.get_entry_and_inode_id(new_name)? {
+            // already there under the new name
+            Some((victim_id,_)) if victim_id==inode_id => return Ok(()),
+            Some((victim_id,victim_entry_id)) => {
+                let victim=self.fs.get_inode(victim_id)?;
+                let victim_is_dir=victim.disk_inode.read().type_==FileType::Dir;
+                match (is_dir,victim_is_dir) {
+                    (false,true) => return Err(FsError::IsDir),
+                    (true,false) => return Err(FsError::NotDir),
+                    (true,true) if victim.disk_inode.read().size as usize>2*DIRENT_SIZE => {
+                        return Err(FsError::DirNotEmpty);
+                    }
+                    _ => {}
+                }
+                // the name never disappears: the entry is overwritten in place
+                dest.write_dir_entry(victim_entry_id,&new_entry)?;
+                victim.disk_inode.write().nlinks-=1;
+                if victim_is_dir {
+                    victim.disk_inode.write().nlinks-=1;
+                    dest.disk_inode.write().nlinks-=1;
+                }
+                victim.changed();
+            }
+            None => dest.append_dir_entry(&new_entry)?,
+        }
+        // appending or overwriting in `dest` never moves the old entry
+        self.remove_dir_entry(entry_id)?;
+
+        if is_dir && dest.id!=self.id {
+            inode.write_dir_entry(1,&DiskEntry{
+                inode_id: dest.id as u32,
+                name: Str256::from(".."),
+            })?;
+            self.disk_inode.write().nlinks-=1;
+            dest.disk_inode.write().nlinks+=1;
+        }
+        inode.changed();
+        self.modified();
+        dest.modified();
+        Ok(())
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.check_dir_alive()?;
+        if name=="." || name==".." {
+            return Err(FsError::IsDir);
+        }
+        let (inode_id,entry_id)=self.get_entry_and_inode_id(name)?.ok_or(FsError::EntryNotFound)?;
+        let inode=self.fs.get_inode(inode_id)?;
+        let is_dir=inode.disk_inode.read().type_==FileType::Dir;
+        if is_dir && inode.disk_inode.read().size as usize>2*DIRENT_SIZE {
+            return Err(FsError::DirNotEmpty);
+        }
+        self.fs.reserve(self.entry_blocks()+1)?;
+
+        self.remove_dir_entry(entry_id)?;
+        inode.disk_inode.write().nlinks-=1;
+        if is_dir {
+            // its "." and its ".." to us
+            inode.disk_inode.write().nlinks-=1;
+            self.disk_inode.write().nlinks-=1;
+        }
+        inode.changed();
+        self.modified();
+        // the blocks are released when the last reference to `inode` is dropped
+        Ok(())
+    }
     fn find(&self, name: &str) ->Result<Arc<dyn Inode>> {
-        let id=self.get_entry_and_inode_id(name).ok_or(FsError::EntryNotFound)?.0;
-        let inode= self.fs.get_inode(id);
+        if self.disk_inode.read().type_!=FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        let id=self.get_entry_and_inode_id(name)?.ok_or(FsError::EntryNotFound)?.0;
+        let inode= self.fs.get_inode(id)?;
         Ok(inode)
     }
     fn get_entry_with_meta_data(&self, entry_id: usize) -> Result<(MetaData, String)> {
-        if self.metadata()?.type_!=FileType::Dir{return Err(FsError::NotDir);}
-        let disk_entry=self.read_dir_entry(entry_id).map_err(FsError::EntryNotFound)?;
-        let meta_data=self.fs.get_inode(entry_id).metadata()?;
-        Ok ((meta_data,String::from(disk_entry.name.as_ref())))
+        let disk_entry=self.nth_dir_entry(entry_id)?;
+        let meta_data=self.fs.get_inode(disk_entry.inode_id as InodeId)?.metadata()?;
+        Ok ((meta_data,String::from(disk_entry.name.as_str()?)))
     }
 
     fn get_entry(&self, entry_id: usize) -> Result<String> {
+        let name=self.nth_dir_entry(entry_id)?.name;
+        Ok(String::from(name.as_str()?))
+    }
+
+    /// the position of an entry is its slot, free slots are skipped
+    fn read_dir_at(&self, cookie: usize) -> Result<Vec<(DirEntry, usize)>> {
         if self.disk_inode.read().type_ != FileType::Dir {
             return Err(FsError::NotDir);
         }
-        if entry_id >= self.disk_inode.read().size as usize / DIRENT_SIZE {
-            return Err(FsError::EntryNotFound);
+        self.fs.reserve(1)?;
+        let mut disk_entries=Vec::new();
+        self.scan_dir(cookie,|slot,disk_entry| {
+            if !disk_entry.is_free() {
+                disk_entries.push((disk_entry.clone(),slot+1));
+            }
+            (disk_entries.len()==DIRENT_BATCH).then_some(())
+        })?;
+        let entries=disk_entries.into_iter().map(|(disk_entry,next)| {
+            let inode_id=disk_entry.inode_id as InodeId;
+            let file_type=self.fs.file_type(inode_id)?;
+            Ok((DirEntry{
+                name: String::from(disk_entry.name.as_str()?),
+                inode_id,
+                file_type,
+            },next))
+        }).collect::<Result<Vec<_>>>()?;
+        self.accessed();
+        Ok(entries)
+    }
+
+    fn read_at(&self,offset:usize,buf:&mut [u8])->Result<usize>{
+        match self.disk_inode.read().type_ {
+            FileType::File => {}
+            FileType::CharDevice | FileType::BlockDevice => {
+                return Ok(self.device()?.read_at(offset,buf)?);
+            }
+            _ => return Err(FsError::NotFile),
+        }
+        self.fs.reserve(1)?;
+        let len=self._read_at(offset,buf)?;
+        self.accessed();
+        Ok(len)
+    }
+
+    fn write_at(&self,offset:usize,buf:&[u8])->Result<usize>{
+        match self.disk_inode.read().type_ {
+            FileType::File => {}
+            FileType::CharDevice | FileType::BlockDevice => {
+                return Ok(self.device()?.write_at(offset,buf)?);
+            }
+            _ => return Err(FsError::NotFile),
+        }
+        let end=offset.checked_add(buf.len()).ok_or(FsError::InvalidParam)?;
+        // writing past the end extends the file
+        self.grow(end)?;
+        let len=self.write_steps(offset,buf)?;
+        self.modified();
+        Ok(len)
+    }
+
+    fn append(&self,buf:&[u8])->Result<(usize,usize)>{
+        match self.disk_inode.read().type_ {
+            FileType::File => {}
+            // a device has no end to append to
+            FileType::CharDevice | FileType::BlockDevice => return Ok((0,self.write_at(0,buf)?)),
+            _ => return Err(FsError::NotFile),
+        }
+        // the range is reserved under the lock, so concurrent appends never overlap,
+        // the file grows at once: one transaction holds all of it
+        let blocks=self.grow_blocks(&self.disk_inode.read(),buf.len().div_ceil(BLKSIZE)+1);
+        self.fs.reserve(blocks)?;
+        let offset={
+            let mut disk_inode=self.disk_inode.write();
+            let offset=disk_inode.size as usize;
+            self._resize_locked(&mut disk_inode,offset+buf.len())?;
+            offset
         };
-        let name=self.read_dir_entry(entry_id)?.name;
-        Ok(String::from(name.as_ref()))
+        let len=self.write_steps(offset,buf)?;
+        self.modified();
+        Ok((offset,len))
     }
 
-    fn read_at(&self,offset:usize,buf:&mut [u8])->Result<usize>{Err(FsError::NotSupported)}
+    fn resize(&self,len:usize)->Result<()>{
+        if self.disk_inode.read().type_!=FileType::File {
+            return Err(FsError::NotFile);
+        }
+        // growing goes in steps, shrinking at once: the blocks are not written
+        self.grow(len)?;
+        let blocks=self.grow_blocks(&self.disk_inode.read(),0);
+        self.fs.reserve(blocks)?;
+        self._resize(len)?;
+        self.modified();
+        Ok(())
+    }
 
-    fn write_at(&self,offset:usize,buf:&[u8])->Result<usize>{Err(FsError::NotSupported)}
+    fn sync_all(&self)->Result<()>{
+        // the block map of the inode means nothing without the free map and the super block,
+        // with a journal the disk inode can only be written as a part of a transaction of the whole fs
+        self.fs.sync()
+    }
 
-    fn resize(&self)->Result<()>{
-        Err(FsError::NotSupported)
+    fn sync_data(&self)->Result<()>{
+        // data goes to the device directly, only the size and the block map are cached
+        self.sync_all()
     }
 
     fn fs(&self) -> Arc<dyn FileSystem> {
         self.fs.clone()
     }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
+}
+
+impl Drop for InodeImpl{
+    /// write back the disk inode before it is gone,
+    /// or release everything if no link is left
+    fn drop(&mut self){
+        let result=if self.disk_inode.read().nlinks==0 {
+            self.release()
+        }else{
+            // flushed, or committed with a journal, by the next sync of the fs
+            self.sync_disk_inode()
+        };
+        if let Err(err)=result {
+            // nobody to report to: the changes are lost, but the kernel goes on
+            log::error!("bfs: failed to write back inode {} when dropping: {}", self.id, err);
+            self.disk_inode.write().sync();
+        }
+    }
 }
 
 
 impl InodeImpl{
-    /// /////////////////////////////////////////////////////
-    ///                       construct func                                          //
-    /// /////////////////////////////////////////////////////
+    // /////////////////////////////////////////////////////
+    //                       construct func                                          //
+    // /////////////////////////////////////////////////////
     pub fn new(id: InodeId,
            disk_inode: RwLock<Dirty<DiskINode>>,
            fs: Arc<JCBFileSystem>,
@@ -128,39 +433,298 @@ impl InodeImpl{
     }
 
 
-    /// /////////////////////////////////////////////////////
-    ///                       tool func                                          //
-    /// /////////////////////////////////////////////////////
+    // /////////////////////////////////////////////////////
+    //                       tool func                                          //
+    // /////////////////////////////////////////////////////
 
     /// transform block id from the virtual to the real
     fn get_disk_block_id(&self,file_block_id:BlockId)->Result<usize>{
-        Ok(1)
+        self._get_disk_block_id(&self.disk_inode.read(),file_block_id)
+    }
+
+    fn _get_disk_block_id(&self,disk_inode:&DiskINode,file_block_id:BlockId)->Result<usize>{
+        if disk_inode.flags&INODE_EXTENTS!=0 {
+            if file_block_id>=disk_inode.blocks as usize {
+                return Err(FsError::InvalidParam);
+            }
+            return extent::lookup(&self.fs,&disk_inode.extents,file_block_id)?.ok_or(FsError::InvalidParam);
+        }
+        match file_block_id {
+            id if id>=disk_inode.blocks as usize || id>=MAX_NBLOCK_DOUBLE_INDIRECT => Err(FsError::InvalidParam),
+            id if id<MAX_NBLOCK_DIRECT => Ok(disk_inode.direct[id] as usize),
+            id if id<MAX_NBLOCK_INDIRECT => {
+                self.read_entry(disk_inode.indirect as usize,id-MAX_NBLOCK_DIRECT)
+            }
+            id => {
+                // the double indirect block points to indirect blocks
+                let id=id-MAX_NBLOCK_INDIRECT;
+                let indirect=self.read_entry(disk_inode.db_indirect as usize,id/BLK_NENTRY)?;
+                self.read_entry(indirect,id%BLK_NENTRY)
+            }
+        }
+    }
+
+    /// map `file_block_id` to `disk_block_id`, allocating the (double) indirect blocks on the way
+    ///
+    /// with extents, only the block right after the last one can be mapped
+    fn set_disk_block_id(&self,disk_inode:&mut DiskINode,file_block_id:BlockId,disk_block_id:BlockId)->Result<()>{
+        if disk_inode.flags&INODE_EXTENTS!=0 {
+            return extent::append(&self.fs,&mut disk_inode.extents,file_block_id,disk_block_id);
+        }
+        match file_block_id {
+            id if id<MAX_NBLOCK_DIRECT => {
+                disk_inode.direct[id]=disk_block_id as u32;
+                Ok(())
+            }
+            id if id<MAX_NBLOCK_INDIRECT => {
+                if disk_inode.indirect==0 {
+                    disk_inode.indirect=self.alloc_zeroed_block(&self.fs.meta,None)? as u32;
+                }
+                self.write_entry(disk_inode.indirect as usize,id-MAX_NBLOCK_DIRECT,disk_block_id)
+            }
+            id if id<MAX_NBLOCK_DOUBLE_INDIRECT => {
+                if disk_inode.db_indirect==0 {
+                    disk_inode.db_indirect=self.alloc_zeroed_block(&self.fs.meta,None)? as u32;
+                }
+                let id=id-MAX_NBLOCK_INDIRECT;
+                let db_indirect=disk_inode.db_indirect as usize;
+                let mut indirect=self.read_entry(db_indirect,id/BLK_NENTRY)?;
+                if indirect==0 {
+                    indirect=self.alloc_zeroed_block(&self.fs.meta,None)?;
+                    self.write_entry(db_indirect,id/BLK_NENTRY,indirect)?;
+                }
+                self.write_entry(indirect,id%BLK_NENTRY,disk_block_id)
+            }
+            _ => Err(FsError::InvalidParam),
+        }
+    }
+
+    /// read the `entry_id`-th block pointer of the (double) indirect block
+    fn read_entry(&self,block_id:BlockId,entry_id:usize)->Result<BlockId>{
+        let mut disk_block_id:u32=0;
+        self.fs.meta.read_block(block_id,entry_id*ENTRY_SIZE,disk_block_id.as_buf_mut())?;
+        Ok(disk_block_id as BlockId)
+    }
+
+    /// write the `entry_id`-th block pointer of the (double) indirect block
+    fn write_entry(&self,block_id:BlockId,entry_id:usize,disk_block_id:BlockId)->Result<()>{
+        self.fs.meta.write_block(block_id,entry_id*ENTRY_SIZE,(disk_block_id as u32).as_buf())
+    }
+
+    /// a new block, zeroed through `device`, the fs device or the metadata one
+    fn alloc_zeroed_block(&self,device:&Arc<dyn Device>,hint:Option<BlockId>)->Result<BlockId>{
+        let id=self.fs.alloc_block(hint).ok_or(FsError::NoDeviceSpace)?;
+        device.write_block(id,0,&[0u8;BLKSIZE])?;
+        Ok(id)
     }
 
     /// resize the file
+    ///
+    /// new blocks are zeroed, blocks out of `len` go back to the free map
     fn _resize(&self,len:usize)->Result<()>{
-       Ok(())
+        self._resize_locked(&mut self.disk_inode.write(),len)
+    }
+
+    /// `_resize` with the disk inode already locked
+    fn _resize_locked(&self,disk_inode:&mut Dirty<DiskINode>,len:usize)->Result<()>{
+        if len as u64>max_size(disk_inode) {
+            return Err(FsError::InvalidParam);
+        }
+        let old_size=disk_inode.size as usize;
+        let old_blocks=disk_inode.blocks as usize;
+        let blocks=len.div_ceil(BLKSIZE);
+
+        if len>old_size && !old_size.is_multiple_of(BLKSIZE) {
+            // the tail of the last block may keep the content before a shrink
+            let block_id=self._get_disk_block_id(disk_inode,old_size/BLKSIZE)?;
+            let begin=old_size%BLKSIZE;
+            self.write_data_block(disk_inode,block_id,begin,&[0u8;BLKSIZE][begin..])?;
+        }
+
+        // grow, right behind the last block if possible
+        let mut hint=match old_blocks {
+            0 => None,
+            n => Some(self._get_disk_block_id(disk_inode,n-1)?+1),
+        };
+        for i in old_blocks..blocks{
+            let id=self.alloc_zeroed_block(self.fs.data_device(disk_inode),hint)?;
+            if self.checksummed(disk_inode) {
+                self.fs.set_block_checksum(id,ZERO_BLOCK_CHECKSUM)?;
+            }
+            self.set_disk_block_id(disk_inode,i,id)?;
+            disk_inode.blocks+=1;
+            hint=Some(id+1);
+        }
+
+        // shrink
+        if blocks<old_blocks && disk_inode.flags&INODE_EXTENTS!=0 {
+            extent::truncate(&self.fs,&mut disk_inode.extents,blocks)?;
+            disk_inode.blocks=blocks as u32;
+        } else if blocks<old_blocks {
+            for i in blocks..old_blocks{
+                let id=self._get_disk_block_id(disk_inode,i)?;
+                self.fs.free_block(id)?;
+            }
+            if old_blocks>MAX_NBLOCK_INDIRECT {
+                let db_indirect=disk_inode.db_indirect as usize;
+                let keep=blocks.saturating_sub(MAX_NBLOCK_INDIRECT).div_ceil(BLK_NENTRY);
+                let used=(old_blocks-MAX_NBLOCK_INDIRECT).div_ceil(BLK_NENTRY);
+                for i in keep..used{
+                    let indirect=self.read_entry(db_indirect,i)?;
+                    self.fs.free_block(indirect)?;
+                    // a double indirect block going away is never written, releasing a file writes no index
+                    if blocks>MAX_NBLOCK_INDIRECT {
+                        self.write_entry(db_indirect,i,0)?;
+                    }
+                }
+                if blocks<=MAX_NBLOCK_INDIRECT {
+                    self.fs.free_block(db_indirect)?;
+                    disk_inode.db_indirect=0;
+                }
+            }
+            if old_blocks>MAX_NBLOCK_DIRECT && blocks<=MAX_NBLOCK_DIRECT {
+                self.fs.free_block(disk_inode.indirect as usize)?;
+                disk_inode.indirect=0;
+            }
+            disk_inode.blocks=blocks as u32;
+        }
+        disk_inode.size=len as u64;
+        Ok(())
+    }
+
+    /// grow the file to `len` bytes, never shrinking what a concurrent writer extended,
+    /// in steps so that each of them fits into a transaction
+    fn grow(&self,len:usize)->Result<()>{
+        if len as u64>max_size(&self.disk_inode.read()) {
+            return Err(FsError::InvalidParam);
+        }
+        loop {
+            let blocks={
+                let disk_inode=self.disk_inode.read();
+                if disk_inode.size as usize>=len {
+                    return Ok(());
+                }
+                self.grow_blocks(&disk_inode,grow_step(&disk_inode))
+            };
+            self.fs.reserve(blocks)?;
+            let mut disk_inode=self.disk_inode.write();
+            let end=((disk_inode.size as usize).div_ceil(BLKSIZE)+grow_step(&disk_inode))*BLKSIZE;
+            if len.min(end)>disk_inode.size as usize {
+                self._resize_locked(&mut disk_inode,len.min(end))?;
+            }
+        }
     }
 
-    /// /////////////////////////////////////////////////////
-    ///                  FOR FILE                          //
-    /// /////////////////////////////////////////////////////
+    /// write the data of a file, in steps if it goes to the journal, so that each of them fits into a transaction
+    fn write_steps(&self,offset:usize,buf:&[u8])->Result<usize>{
+        if !JCBFileSystem::data_is_meta(&self.disk_inode.read()) {
+            return self._write_at(offset,buf);
+        }
+        let mut done=0;
+        while done<buf.len() {
+            let begin=offset+done;
+            let end=((begin/BLKSIZE+JOURNAL_STEP)*BLKSIZE).min(offset+buf.len());
+            // every block with the block of its checksum
+            self.fs.reserve(2*JOURNAL_STEP+1)?;
+            let len=self._write_at(begin,&buf[done..end-offset])?;
+            done+=len;
+            if len<end-begin {
+                break;
+            }
+        }
+        Ok(done)
+    }
+
+    /// an upper bound of the metadata blocks written by growing the file by `blocks` blocks,
+    /// or by shrinking it with none, its slot included
+    fn grow_blocks(&self,disk_inode:&DiskINode,blocks:usize)->usize{
+        // the new blocks and the tail of the last one, each with the block of its checksum
+        let data=match (JCBFileSystem::data_is_meta(disk_inode),self.checksummed(disk_inode)) {
+            (false,_) => 0,
+            (true,false) => blocks+1,
+            (true,true) => 2*(blocks+1),
+        };
+        1+data+index_blocks(disk_inode,blocks)
+    }
+
+    /// an upper bound of the metadata blocks written by adding or removing an entry of the dir
+    pub(crate) fn entry_blocks(&self)->usize{
+        // the entry may straddle two blocks, each with the block of its checksum
+        4+self.grow_blocks(&self.disk_inode.read(),1)
+    }
+
+    /// an upper bound of the metadata blocks written by creating an inode of `blocks` blocks
+    fn new_inode_blocks(&self,mut disk_inode:DiskINode,blocks:usize)->usize{
+        // mapped the way `stamp` maps it
+        if self.fs.super_block.read().features&FEATURE_EXTENTS!=0 {
+            disk_inode.flags|=INODE_EXTENTS;
+        }
+        self.grow_blocks(&disk_inode,blocks)
+    }
+
+    /// the content has been read
+    fn accessed(&self){
+        self.disk_inode.write().atime=self.fs.current_time().into();
+    }
+
+    /// the content has been modified, which changes the inode as well
+    fn modified(&self){
+        let now=self.fs.current_time().into();
+        let mut disk_inode=self.disk_inode.write();
+        disk_inode.mtime=now;
+        disk_inode.ctime=now;
+    }
+
+    /// the inode itself (links, owner, mode) has been changed
+    fn changed(&self){
+        self.disk_inode.write().ctime=self.fs.current_time().into();
+    }
+
+    /// the driver of a device node
+    fn device(&self)->Result<Arc<dyn Device>>{
+        self.fs.device_registry().get_by_rdev(self.device_inode_id).ok_or(FsError::NoDevice)
+    }
+
+    /// give the data blocks back to the free map and the slot back to the inode map
+    fn release(&self)->Result<()>{
+        self._resize(0)?;
+        // the inode slot is free now, never write it back
+        self.disk_inode.write().sync();
+        self.fs.free_inode(self.id)
+    }
+
+    /// write the disk inode back if it is dirty
+    pub(crate) fn sync_disk_inode(&self)->Result<()>{
+        let mut disk_inode=self.disk_inode.write();
+        if disk_inode.dirty(){
+            self.fs.write_disk_inode(self.id,&disk_inode)?;
+            disk_inode.sync();
+        }
+        Ok(())
+    }
+
+    // /////////////////////////////////////////////////////
+    //                  FOR FILE                          //
+    // /////////////////////////////////////////////////////
 
     /// io the Inode
-    fn _io_at<F>(&self,begin:usize,end:usize,io_block:F)->Result<usize>
-    where F:Fn(&Arc<dyn Device>,&BlockRange,usize)->Result<()>
+    fn _io_at<F>(&self,begin:usize,end:usize,mut io_block:F)->Result<usize>
+    where F:FnMut(&Arc<dyn Device>,&BlockRange,usize)->Result<()>
     {
-        let size=self.disk_inode.read().size as usize;
+        let (size,device)={
+            let disk_inode=self.disk_inode.read();
+            (disk_inode.size as usize,self.fs.data_device(&disk_inode))
+        };
         let iter=BlockIter{
             begin: size.min(begin),
             end: size.min(end),
             block_size_log2: BLKSIZE_LOG2,
         };
 
-        let mut buf_off =0 as usize;
+        let mut buf_off =0;
         for mut range in iter{
             range.block=self.get_disk_block_id(range.block)?;
-            io_block(&self.fs.device,&range,buf_off)?;
+            io_block(device,&range,buf_off)?;
             buf_off+=range.len();
         }
         Ok(buf_off)
@@ -170,29 +734,164 @@ impl InodeImpl{
     // file_block_table
     // read the file(offset,len) -> read the virtual file_block(offset,len) -> form the block_iter -> read every real block
     fn _read_at(&self, offset:usize, buf: &mut [u8]) ->Result<usize>{
+        let checked=self.checksummed(&self.disk_inode.read());
+        let mut block=match checked {
+            true => vec![0u8;BLKSIZE],
+            false => Vec::new(),
+        };
         self._io_at(offset,offset+buf.len(),|device,range,offset| {
-            device.read_block(range.block,range.begin,&mut buf[offset..offset+range.len()])
+            let buf=&mut buf[offset..offset+range.len()];
+            if !checked {
+                return device.read_block(range.block,range.begin,buf);
+            }
+            // a block is checked as a whole
+            self.fs.read_checked_block(range.block,&mut block)?;
+            buf.copy_from_slice(&block[range.begin..range.end]);
+            Ok(())
         })
     }
     /// inner write
     fn _write_at(&self,offset:usize,buf:&[u8])->Result<usize>{
-        self._io_at(offset,offset+buf.len(),|device,range,offset|{
-            device.write_block(range.block,range.begin,&mut buf[offset..offset+range.len()])
+        self._io_at(offset,offset+buf.len(),|_,range,offset|{
+            self.write_data_block(&self.disk_inode.read(),range.block,range.begin,&buf[offset..offset+range.len()])
         })
     }
 
+    /// the data blocks carry checksums
+    fn checksummed(&self,disk_inode:&DiskINode)->bool{
+        (disk_inode.type_==FileType::Dir || disk_inode.flags&INODE_CRITICAL!=0) && self.fs.checksums()
+    }
+
+    /// write into a data block from `begin` on, its checksum kept up to date if it carries one
+    fn write_data_block(&self,disk_inode:&DiskINode,block_id:BlockId,begin:usize,buf:&[u8])->Result<()>{
+        if !self.checksummed(disk_inode) {
+            return self.fs.data_device(disk_inode).write_block(block_id,begin,buf);
+        }
+        let mut block=vec![0u8;BLKSIZE];
+        if buf.len()<BLKSIZE {
+            self.fs.read_checked_block(block_id,&mut block)?;
+        }
+        block[begin..begin+buf.len()].copy_from_slice(buf);
+        self.fs.write_checked_block(block_id,&block)
+    }
+
+    /// see `JCBFileSystem::mark_critical`
+    pub(crate) fn mark_critical(&self)->Result<()>{
+        if !self.fs.checksums() {
+            return Err(FsError::NotSupported);
+        }
+        // the checksum of every block, and the slot
+        let checksum_blocks=(self.fs.super_block.read().blocks as usize).div_ceil(CHECKSUMS_PER_BLOCK);
+        let blocks=self.disk_inode.read().blocks as usize;
+        self.fs.reserve(blocks.min(checksum_blocks)+1)?;
+        let mut disk_inode=self.disk_inode.write();
+        if disk_inode.type_!=FileType::File {
+            return Err(FsError::NotFile);
+        }
+        if disk_inode.flags&INODE_CRITICAL!=0 {
+            return Ok(());
+        }
+        // the blocks are on the device, the journal has nothing of them
+        let mut block=vec![0u8;BLKSIZE];
+        for i in 0..disk_inode.blocks as usize {
+            let id=self._get_disk_block_id(&disk_inode,i)?;
+            self.fs.device.read_block(id,0,&mut block)?;
+            self.fs.set_block_checksum(id,crc32c(0,&block))?;
+        }
+        disk_inode.flags|=INODE_CRITICAL;
+        Ok(())
+    }
+
 
-    /// /////////////////////////////////////////////////////
-    ///          FOR DIR                                   //
-    /// /////////////////////////////////////////////////////
+    // /////////////////////////////////////////////////////
+    //          FOR DIR                                   //
+    // /////////////////////////////////////////////////////
 
 
     /// for the dir type,get metadata of subInode by name
-    fn get_entry_and_inode_id(&self,name:&str)->Option<(InodeId,usize)>{
-        (0..self.disk_inode.read().size as usize / DIRENT_SIZE)
-            .map(|i| (self._read_dir_entry(i as usize).unwrap(), i))
-            .find(|(entry, _)| entry.name.as_ref() == name)
-            .map(|(entry, id)| (entry.inode_id as InodeId, id as usize))
+    fn get_entry_and_inode_id(&self,name:&str)->Result<Option<(InodeId,usize)>>{
+        self.scan_dir(0,|slot,entry| {
+            (!entry.is_free() && entry.name.is(name)).then_some((entry.inode_id as InodeId,slot))
+        })
+    }
+
+    /// the `n`th entry in use
+    fn nth_dir_entry(&self,mut n:usize)->Result<DiskEntry>{
+        if self.disk_inode.read().type_!=FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        self.scan_dir(0,|_,entry| {
+            if entry.is_free() {
+                return None;
+            }
+            if n==0 {
+                return Some(entry.clone());
+            }
+            n-=1;
+            None
+        })?.ok_or(FsError::EntryNotFound)
+    }
+
+    /// visit the slots from `begin` on until `f` gives something back,
+    /// reading `DIRENT_BATCH` slots at once
+    fn scan_dir<T,F>(&self,begin:usize,mut f:F)->Result<Option<T>>
+        where F:FnMut(usize,&DiskEntry)->Option<T>
+    {
+        let count=self.disk_inode.read().size as usize/DIRENT_SIZE;
+        let mut buf=vec![0u8;DIRENT_BATCH*DIRENT_SIZE];
+        let mut slot=begin;
+        while slot<count {
+            let n=(count-slot).min(DIRENT_BATCH);
+            let batch=&mut buf[..n*DIRENT_SIZE];
+            self._read_at(slot*DIRENT_SIZE,batch)?;
+            for (i,raw) in batch.chunks_exact(DIRENT_SIZE).enumerate() {
+                let mut entry:DiskEntry=unsafe{uninit_memory()};
+                entry.as_buf_mut().copy_from_slice(raw);
+                if let Some(found)=f(slot+i,&entry) {
+                    return Ok(Some(found));
+                }
+            }
+            slot+=n;
+        }
+        Ok(None)
+    }
+
+    /// the dir is still linked to the tree
+    fn check_dir_alive(&self)->Result<()>{
+        let disk_inode=self.disk_inode.read();
+        if disk_inode.type_!=FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if disk_inode.nlinks==0 {
+            return Err(FsError::DirRemoved);
+        }
+        Ok(())
+    }
+
+    /// `name` can be added to the dir
+    fn check_new_entry(&self,name:&str)->Result<()>{
+        self.check_dir_alive()?;
+        check_name(name)?;
+        if self.get_entry_and_inode_id(name)?.is_some() {
+            return Err(FsError::EntryExist);
+        }
+        Ok(())
+    }
+
+    /// the dir `ancestor` is this dir or one of its parents
+    fn is_descendant_of(&self,ancestor:InodeId)->Result<bool>{
+        let mut id=self.id;
+        loop {
+            if id==ancestor {
+                return Ok(true);
+            }
+            let parent=self.fs.get_inode(id)?.read_dir_entry(1)?.inode_id as InodeId;
+            // ".." of the root is itself
+            if parent==id {
+                return Ok(false);
+            }
+            id=parent;
+        }
     }
 
     pub fn init_dir_entry(&self, parent:InodeId)->Result<()>{
@@ -202,7 +901,7 @@ impl InodeImpl{
         self.write_dir_entry(
             0,
             &DiskEntry{
-                inode_id: self.id,
+                inode_id: self.id as u32,
                 name: Str256::from("."),
             }
         )?;
@@ -210,7 +909,7 @@ impl InodeImpl{
         self.write_dir_entry(
             1,
             &DiskEntry{
-                inode_id: parent,
+                inode_id: parent as u32,
                 name: Str256::from(".."),
             }
         )?;
@@ -219,26 +918,30 @@ impl InodeImpl{
     pub fn read_dir_entry(&self,entry_id:usize)->Result<DiskEntry>{
         if let Some(disk_entry)=self.cache_entrys.read().get(&entry_id){
             // TODO FIXME
-            return Ok((*disk_entry).Clone())
+            return Ok(disk_entry.clone())
         }
         self._read_dir_entry(entry_id)
     }
 
-    fn append_dir_entry(&self,disk_entry:& DiskEntry)->Result<()>{
-       let size=self.disk_inode.read().size;
-        self._resize((size + DIRENT_SIZE) as usize)?;
-        let entry_id=size/DIRENT_SIZE;
-        self.write_dir_entry(entry_id as usize, &disk_entry)?;
+    /// take the first free slot, or a new one at the end
+    pub(crate) fn append_dir_entry(&self,disk_entry:& DiskEntry)->Result<()>{
+        if let Some(slot)=self.scan_dir(2,|slot,entry| entry.is_free().then_some(slot))? {
+            return self.write_dir_entry(slot,disk_entry);
+        }
+        let size=self.disk_inode.read().size;
+        self._resize(size as usize + DIRENT_SIZE)?;
+        let entry_id=size as usize/DIRENT_SIZE;
+        self.write_dir_entry(entry_id, disk_entry)?;
         Ok(())
     }
     pub fn write_dir_entry(&self,id:usize,disk_entry:& DiskEntry)->Result<()>{
-        self.cache_entrys.write().insert(id, (*disk_entry).clone());
+        self.cache_entrys.write().insert(id, disk_entry.clone());
 
         self._write_dir_entry(id,disk_entry)
     }
-    pub fn remove_dir_entry(&self,id:usize){
+    pub fn remove_dir_entry(&self,id:usize)->Result<()>{
         self.cache_entrys.write().remove(&id);
-        self._remove_dir_entry(id);
+        self._remove_dir_entry(id)
     }
 
 
@@ -247,15 +950,674 @@ impl InodeImpl{
 
         let mut buf:DiskEntry=unsafe{uninit_memory()};
         // have specific error
-        self.read_at(entry_id*DIRENT_SIZE,buf.as_buf_mut())?;
+        self._read_at(entry_id*DIRENT_SIZE,buf.as_buf_mut())?;
         Ok(buf)
     }
     fn _write_dir_entry(&self,entry_id:usize,disk_entry:& DiskEntry)->Result<()>{
-        self.write_at(entry_id*DIRENT_SIZE,disk_entry.as_buf())?;
+        self._write_at(entry_id*DIRENT_SIZE,disk_entry.as_buf())?;
         Ok(())
     }
 
-    fn _remove_dir_entry(&self,id:usize){
+    /// free the slot, the free slots at the end are cut off
+    ///
+    /// so a dir is empty exactly when only "." and ".." are left
+    fn _remove_dir_entry(&self,id:usize)->Result<()>{
+        self.write_dir_entry(id,&DiskEntry::free())?;
+        let mut count=self.disk_inode.read().size as usize/DIRENT_SIZE;
+        while count>2 && self.read_dir_entry(count-1)?.is_free() {
+            count-=1;
+            self.cache_entrys.write().remove(&count);
+        }
+        self._resize(count*DIRENT_SIZE)
+    }
+}
 
+/// blocks a file grows by at once, few if they go to the journal
+fn grow_step(disk_inode:&DiskINode)->usize{
+    match JCBFileSystem::data_is_meta(disk_inode) {
+        true => JOURNAL_STEP,
+        false => BLK_NENTRY,
     }
-}
\ No newline at end of file
+}
+
+/// the size a file can grow to
+fn max_size(disk_inode:&DiskINode)->u64{
+    match disk_inode.flags&INODE_EXTENTS {
+        0 => MAX_FILE_SIZE as u64,
+        _ => MAX_EXTENT_FILE_SIZE,
+    }
+}
+
+/// an upper bound of the index blocks written when `blocks` blocks are mapped at the end of a file,
+/// or any number of them unmapped: with extents the right edge of the tree and a new node on every
+/// level for each `EXTENTS_PER_BLOCK` blocks, a level more if the root moves down,
+/// without them the (double) indirect blocks
+fn index_blocks(disk_inode:&DiskINode,blocks:usize)->usize{
+    match disk_inode.flags&INODE_EXTENTS {
+        0 => blocks.div_ceil(BLK_NENTRY)+3,
+        _ => (disk_inode.extents.header.depth as usize+2)*(blocks.div_ceil(EXTENTS_PER_BLOCK)+1),
+    }
+}
+
+/// a valid name of a new entry
+fn check_name(name:&str)->Result<()>{
+    if name.is_empty() || name=="." || name==".." || name.len()>MAX_FNAME_LEN
+        || name.contains('/') || name.contains('\0') {
+        return Err(FsError::InvalidParam);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::FormatOptions;
+    use crate::structs::{FEATURE_EXTENTS, INO_ROOT};
+    use crate::test::{MemDevice, clock};
+    use alloc::format;
+    use fs_jcb::{Credentials, DevError, ManualClock, OpenFlags, SeekFrom, NullDevice, Timespec, ZeroDevice, make_rdev};
+
+    /// a write cache in front of `device`, a write only sticks once it is synced
+    struct CacheDevice {
+        cache: Arc<MemDevice>,
+        device: Arc<MemDevice>,
+    }
+
+    impl Device for CacheDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> core::result::Result<usize, DevError> {
+            self.cache.read_at(offset, buf)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> core::result::Result<usize, DevError> {
+            self.cache.write_at(offset, buf)
+        }
+        fn sync(&self) -> core::result::Result<(), DevError> {
+            self.device.0.lock().clone_from(&self.cache.0.lock());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn disk_block_id() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+
+        // hand-made map: indirect block 100, double indirect block 101 -> indirect block 102
+        let inode = fs.new_inode_file().unwrap();
+        {
+            let mut disk_inode = inode.disk_inode.write();
+            disk_inode.nlinks = 1;
+            disk_inode.blocks = MAX_NBLOCK_DOUBLE_INDIRECT as u32;
+            disk_inode.direct[3] = 50;
+            disk_inode.indirect = 100;
+            disk_inode.db_indirect = 101;
+        }
+        let write_entry = |block: BlockId, entry: usize, value: u32| {
+            fs.device.write_block(block, entry * ENTRY_SIZE, value.as_buf()).unwrap();
+        };
+        write_entry(100, 0, 60);
+        write_entry(100, BLK_NENTRY - 1, 61);
+        write_entry(101, 1, 102);
+        write_entry(102, 2, 70);
+
+        assert_eq!(inode.get_disk_block_id(3), Ok(50));
+        assert_eq!(inode.get_disk_block_id(MAX_NBLOCK_DIRECT), Ok(60));
+        assert_eq!(inode.get_disk_block_id(MAX_NBLOCK_INDIRECT - 1), Ok(61));
+        assert_eq!(inode.get_disk_block_id(MAX_NBLOCK_INDIRECT + BLK_NENTRY + 2), Ok(70));
+        assert_eq!(
+            inode.get_disk_block_id(MAX_NBLOCK_DOUBLE_INDIRECT),
+            Err(FsError::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn sync_all() {
+        let device = MemDevice::new(1 << 20);
+        let cached = Arc::new(CacheDevice { cache: MemDevice::new(1 << 20), device: device.clone() });
+        let fs = JCBFileSystem::create(cached, 1 << 20, clock()).unwrap();
+        fs.sync().unwrap();
+
+        let file = fs.root_inode().unwrap().create("file", FileType::File, 0o644).unwrap();
+        file.write_at(0, &[1; 4 * BLKSIZE]).unwrap();
+        file.sync_all().unwrap();
+        // the power goes off: only what was synced is there
+        let synced = MemDevice::new(0);
+        synced.0.lock().clone_from(&device.0.lock());
+
+        let fs = JCBFileSystem::open(synced, clock()).unwrap();
+        assert_eq!(crate::fsck::check(&fs, false).unwrap(), []);
+        let file = fs.root_inode().unwrap().find("file").unwrap();
+        assert_eq!(file.metadata().unwrap().size, 4 * BLKSIZE);
+    }
+
+    #[test]
+    fn resize() {
+        let device = MemDevice::new(16 << 20);
+        let fs = JCBFileSystem::create(device, 16 << 20, clock()).unwrap();
+        let file = fs.new_inode_file().unwrap();
+        let unused = || fs.super_block.read().unused_blocks;
+        let before = unused();
+
+        // direct + indirect + 3 blocks behind the double indirect block
+        let blocks = MAX_NBLOCK_INDIRECT + BLK_NENTRY + 3;
+        file.resize(blocks * BLKSIZE).unwrap();
+        assert_eq!(file.disk_inode.read().blocks as usize, blocks);
+        // the indirect block, the double indirect block and 2 indirect blocks under it
+        assert_eq!(before - unused(), blocks as u32 + 4);
+
+        file._write_at(10, &[1u8; 100]).unwrap();
+        file.resize(20).unwrap();
+        assert_eq!(file.disk_inode.read().blocks, 1);
+        assert_eq!(file.disk_inode.read().indirect, 0);
+        assert_eq!(file.disk_inode.read().db_indirect, 0);
+        assert_eq!(before - unused(), 1);
+
+        // the truncated tail reads back as zero after growing again
+        file.resize(200).unwrap();
+        let mut buf = [0xffu8; 200];
+        assert_eq!(file._read_at(0, &mut buf), Ok(200));
+        assert!(buf[10..20].iter().all(|&b| b == 1));
+        assert!(buf[20..].iter().all(|&b| b == 0));
+
+        file.resize(0).unwrap();
+        assert_eq!(before, unused());
+
+        assert_eq!(fs.root_inode().unwrap().resize(0), Err(FsError::NotFile));
+    }
+
+    #[test]
+    fn extents() {
+        let device = MemDevice::new(16 << 20);
+        let options = FormatOptions { features: FEATURE_EXTENTS, ..FormatOptions::default() };
+        let fs = JCBFileSystem::create_with(device.clone(), 16 << 20, options, clock()).unwrap();
+        let unused = || fs.super_block.read().unused_blocks;
+        let before = unused();
+        let root = fs.root_inode().unwrap();
+        let file = root.create("file", FileType::File, 0o644).unwrap();
+
+        // far more blocks than the block map has direct and indirect pointers, in one extent
+        let blocks = MAX_NBLOCK_INDIRECT + 100;
+        let data: alloc::vec::Vec<u8> = (0..blocks * BLKSIZE).map(|i| (i / BLKSIZE) as u8).collect();
+        assert_eq!(file.write_at(0, &data), Ok(data.len()));
+        let inode = fs.get_inode(file.metadata().unwrap().inode_id).unwrap();
+        assert_eq!(inode.disk_inode.read().flags & INODE_EXTENTS, INODE_EXTENTS);
+        assert_eq!(inode.disk_inode.read().extents.header.entries, 1);
+        assert_eq!(inode.disk_inode.read().extents.entries[0].len as usize, blocks);
+        assert_eq!(before - unused(), blocks as u32);
+        drop((file, inode, root, fs));
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let file = root.find("file").unwrap();
+        let mut buf = alloc::vec![0u8; 2 * BLKSIZE];
+        assert_eq!(file.read_at((blocks - 2) * BLKSIZE, &mut buf), Ok(2 * BLKSIZE));
+        assert_eq!(&buf[..], &data[(blocks - 2) * BLKSIZE..]);
+
+        file.resize(BLKSIZE + 1).unwrap();
+        assert_eq!(file.read_at(BLKSIZE, &mut buf), Ok(1));
+        assert_eq!(buf[0], 1);
+        drop(file);
+        root.unlink("file").unwrap();
+        assert_eq!(fs.super_block.read().unused_blocks, before);
+    }
+
+    #[test]
+    fn read_write() {
+        let device = MemDevice::new(16 << 20);
+        let fs = JCBFileSystem::create(device.clone(), 16 << 20, clock()).unwrap();
+        let root = fs.get_inode(INO_ROOT).unwrap();
+        let file = root.create("file", FileType::File, 0o644).unwrap();
+
+        // across the direct and the indirect blocks
+        let offset = MAX_NBLOCK_DIRECT * BLKSIZE - 100;
+        let data: alloc::vec::Vec<u8> = (0..300).map(|i| i as u8).collect();
+        assert_eq!(file.write_at(offset, &data), Ok(300));
+
+        let mut buf = [0u8; 400];
+        // short read at EOF
+        assert_eq!(file.read_at(offset, &mut buf), Ok(300));
+        assert_eq!(&buf[..300], &data[..]);
+        assert_eq!(file.read_at(offset + 300, &mut buf), Ok(0));
+        // the hole before the data is zero
+        assert_eq!(file.read_at(0, &mut buf), Ok(400));
+        assert!(buf.iter().all(|&b| b == 0));
+
+        // overwrite inside the file keeps its size
+        assert_eq!(file.write_at(offset + 10, &[0xff; 10]), Ok(10));
+        let inode = fs.get_inode(root.get_entry_and_inode_id("file").unwrap().unwrap().0).unwrap();
+        assert_eq!(inode.disk_inode.read().size as usize, offset + 300);
+        assert_eq!(inode.disk_inode.read().blocks as usize, MAX_NBLOCK_DIRECT + 1);
+
+        assert_eq!(root.read_at(0, &mut buf), Err(FsError::NotFile));
+        assert_eq!(root.write_at(0, &buf), Err(FsError::NotFile));
+
+        // everything goes to the disk
+        drop((file, inode, root));
+        drop(fs);
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let file = fs.root_inode().unwrap().find("file").unwrap();
+        assert_eq!(file.read_at(offset + 10, &mut buf), Ok(290));
+        assert_eq!(&buf[..10], &[0xff; 10]);
+        assert_eq!(&buf[10..290], &data[20..]);
+    }
+
+    #[test]
+    fn unlink() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device, 1 << 20, clock()).unwrap();
+        let unused = || fs.super_block.read().unused_blocks;
+        let before = unused();
+        let root = fs.get_inode(INO_ROOT).unwrap();
+
+        let file = root.create("file", FileType::File, 0o644).unwrap();
+        file.write_at(0, &[1u8; 3 * BLKSIZE]).unwrap();
+        let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+        dir.create("sub", FileType::File, 0o644).unwrap();
+        assert_eq!(root.disk_inode.read().nlinks, 3);
+        assert_eq!(root.create("file", FileType::Dir, 0o755).err(), Some(FsError::EntryExist));
+
+        assert_eq!(root.unlink("dir"), Err(FsError::DirNotEmpty));
+        assert_eq!(root.unlink(".."), Err(FsError::IsDir));
+        assert_eq!(root.unlink("none"), Err(FsError::EntryNotFound));
+
+        // an open file keeps its blocks after the last link is gone
+        root.unlink("file").unwrap();
+        assert_eq!(root.find("file").err(), Some(FsError::EntryNotFound));
+        assert_eq!(file.read_at(BLKSIZE, &mut [0u8; 4]), Ok(4));
+        drop(file);
+
+        dir.unlink("sub").unwrap();
+        root.unlink("dir").unwrap();
+        assert_eq!(root.disk_inode.read().nlinks, 2);
+        assert_eq!(dir.create("new", FileType::File, 0o644).err(), Some(FsError::DirRemoved));
+        drop(dir);
+
+        assert_eq!(root.get_entry(2).err(), Some(FsError::EntryNotFound));
+        assert_eq!(before, unused());
+    }
+
+    #[test]
+    fn link() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device, 1 << 20, clock()).unwrap();
+        let root = fs.get_inode(INO_ROOT).unwrap();
+
+        let file = root.create("file", FileType::File, 0o644).unwrap();
+        file.write_at(0, b"data").unwrap();
+        let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+        dir.link("same", &file).unwrap();
+        let inode = fs.get_inode(root.get_entry_and_inode_id("file").unwrap().unwrap().0).unwrap();
+        assert_eq!(inode.disk_inode.read().nlinks, 2);
+        assert_eq!(dir.link("same", &file), Err(FsError::EntryExist));
+        assert_eq!(root.link("dir2", &dir), Err(FsError::IsDir));
+
+        let other = JCBFileSystem::create(MemDevice::new(1 << 20), 1 << 20, clock()).unwrap();
+        assert_eq!(other.root_inode().unwrap().link("file", &file), Err(FsError::NotSameFs));
+
+        // the data survives the original name
+        drop((file, inode));
+        root.unlink("file").unwrap();
+        let mut buf = [0u8; 4];
+        assert_eq!(dir.find("same").unwrap().read_at(0, &mut buf), Ok(4));
+        assert_eq!(&buf, b"data");
+        // no more links than the count holds
+        let same = dir.find("same").unwrap();
+        let inode = fs.get_inode(same.metadata().unwrap().inode_id).unwrap();
+        inode.disk_inode.write().nlinks = u16::MAX;
+        assert_eq!(root.link("full", &same), Err(FsError::TooManyLinks));
+        inode.disk_inode.write().nlinks = 1;
+
+        // an unlinked file does not come back
+        dir.unlink("same").unwrap();
+        assert_eq!(root.link("back", &same), Err(FsError::EntryNotFound));
+        assert_eq!(root.find("back").err(), Some(FsError::EntryNotFound));
+    }
+
+    #[test]
+    fn move_() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device, 1 << 20, clock()).unwrap();
+        let unused = || fs.super_block.read().unused_blocks;
+        let root: Arc<dyn Inode> = fs.get_inode(INO_ROOT).unwrap();
+
+        // write to a temp file, then replace the real one
+        let old = root.create("config", FileType::File, 0o644).unwrap();
+        old.write_at(0, &[0u8; 2 * BLKSIZE]).unwrap();
+        drop(old);
+        let before = unused();
+        let tmp = root.create("config.tmp", FileType::File, 0o644).unwrap();
+        tmp.write_at(0, b"new").unwrap();
+        root.move_("config.tmp", &root, "config").unwrap();
+        assert_eq!(root.find("config.tmp").err(), Some(FsError::EntryNotFound));
+        let mut buf = [0u8; 3];
+        assert_eq!(root.find("config").unwrap().read_at(0, &mut buf), Ok(3));
+        assert_eq!(&buf, b"new");
+        // the inode and 2 blocks of the replaced file are released, the new file takes 2
+        assert_eq!(unused() - before, 1);
+
+        // move a dir across dirs
+        let a = root.create("a", FileType::Dir, 0o755).unwrap();
+        let b = root.create("b", FileType::Dir, 0o755).unwrap();
+        let sub = a.create("sub", FileType::Dir, 0o755).unwrap();
+        a.move_("sub", &b, "moved").unwrap();
+        let nlinks = |dir: &Arc<dyn Inode>| dir.downcast_ref::<InodeImpl>().unwrap().disk_inode.read().nlinks;
+        assert_eq!(nlinks(&a), 2);
+        assert_eq!(nlinks(&b), 3);
+        assert_eq!(nlinks(&sub), 2);
+        let parent = sub.find("..").unwrap();
+        assert_eq!(parent.downcast_ref::<InodeImpl>().unwrap().id, b.downcast_ref::<InodeImpl>().unwrap().id);
+
+        // never into its own subtree
+        assert_eq!(root.move_("b", &sub, "loop"), Err(FsError::InvalidParam));
+        assert_eq!(b.move_("moved", &sub, "self"), Err(FsError::InvalidParam));
+        // a dir replaces only an empty dir
+        sub.create("file", FileType::File, 0o644).unwrap();
+        assert_eq!(root.move_("a", &b, "moved"), Err(FsError::DirNotEmpty));
+        assert_eq!(root.move_("config", &b, "moved"), Err(FsError::IsDir));
+        assert_eq!(root.move_("a", &sub, "file"), Err(FsError::NotDir));
+        sub.unlink("file").unwrap();
+        root.move_("a", &b, "moved").unwrap();
+        assert_eq!(nlinks(&root), 3);
+        assert_eq!(nlinks(&b), 3);
+    }
+
+    #[test]
+    fn symlink() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device, 1 << 20, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+
+        // longer than a block
+        let target: String = core::iter::repeat_n("dir/", BLKSIZE / 2).collect();
+        let link = root.create_symlink("link", &target).unwrap();
+        assert_eq!(link.read_link(), Ok(target.clone()));
+        assert_eq!(root.find("link").unwrap().read_link(), Ok(target));
+        assert_eq!(root.read_link(), Err(FsError::InvalidParam));
+        assert_eq!(root.create_symlink("link", "x").err(), Some(FsError::EntryExist));
+
+        // no data through read_at
+        assert_eq!(link.read_at(0, &mut [0u8; 4]), Err(FsError::NotFile));
+    }
+
+    #[test]
+    fn mknod() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        root.mknod("null", FileType::CharDevice, 0o666, make_rdev(1, 3)).unwrap();
+        root.mknod("zero", FileType::CharDevice, 0o666, make_rdev(1, 5)).unwrap();
+        assert_eq!(
+            root.mknod("file", FileType::File, 0o666, 0).err(),
+            Some(FsError::InvalidParam)
+        );
+        drop((root, fs));
+
+        // the nodes live in the image, the drivers are registered at runtime
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        fs.device_registry().register(1, 3, Arc::new(NullDevice));
+        let root = fs.root_inode().unwrap();
+        let mut buf = [1u8; 16];
+        let null = root.find("null").unwrap();
+        assert_eq!(null.read_at(0, &mut buf), Ok(0));
+        assert_eq!(null.write_at(0, &buf), Ok(16));
+        let zero = root.find("zero").unwrap();
+        assert_eq!(zero.read_at(0, &mut buf), Err(FsError::NoDevice));
+        fs.device_registry().register(1, 5, Arc::new(ZeroDevice));
+        assert_eq!(zero.read_at(0, &mut buf), Ok(16));
+        assert_eq!(buf, [0u8; 16]);
+        assert_eq!(zero.resize(0), Err(FsError::NotFile));
+    }
+
+    #[test]
+    fn metadata() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let file = root.create("file", FileType::File, 0o4640).unwrap();
+        file.write_at(BLKSIZE, &[1]).unwrap();
+        root.mknod("null", FileType::CharDevice, 0o666, make_rdev(1, 3)).unwrap();
+        drop((file, root, fs));
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let meta = root.metadata().unwrap();
+        assert_eq!(meta.type_, FileType::Dir);
+        assert_eq!(meta.inode_id, INO_ROOT);
+        assert_eq!(meta.size, 4 * DIRENT_SIZE);
+        assert_eq!(meta.permission, 0o755);
+
+        let file = root.find("file").unwrap();
+        let meta = file.metadata().unwrap();
+        assert_eq!(meta.type_, FileType::File);
+        assert_eq!(meta.size, BLKSIZE + 1);
+        assert_eq!(meta.blk_size, BLKSIZE);
+        assert_eq!(meta.blocks, 2);
+        assert_eq!(meta.permission, 0o4640);
+        assert_eq!(meta.rdev, 0);
+
+        let meta = root.find("null").unwrap().metadata().unwrap();
+        assert_eq!(meta.type_, FileType::CharDevice);
+        assert_eq!(meta.permission, 0o666);
+        assert_eq!(meta.rdev, make_rdev(1, 3));
+    }
+
+    #[test]
+    fn set_metadata() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let file = root.create("file", FileType::File, 0o644).unwrap();
+        let mut meta = file.metadata().unwrap();
+        meta.permission = 0o1600;
+        meta.uid = 1000;
+        meta.gid = 100;
+        meta.atime = Timespec { sec: 10, nsec: 1 };
+        meta.mtime = Timespec { sec: 20, nsec: 2 };
+        file.set_metadata(&meta).unwrap();
+        // size and type are not settable this way
+        meta.size = 100;
+        meta.type_ = FileType::Dir;
+        file.set_metadata(&meta).unwrap();
+        meta.uid = usize::MAX;
+        assert_eq!(file.set_metadata(&meta), Err(FsError::InvalidParam));
+        drop((file, root, fs));
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let file = fs.root_inode().unwrap().find("file").unwrap();
+        let meta = file.metadata().unwrap();
+        assert_eq!(meta.permission, 0o1600);
+        assert_eq!((meta.uid, meta.gid), (1000, 100));
+        assert_eq!(meta.atime, Timespec { sec: 10, nsec: 1 });
+        assert_eq!(meta.mtime, Timespec { sec: 20, nsec: 2 });
+        assert_eq!(meta.size, 0);
+        assert_eq!(meta.type_, FileType::File);
+
+        // any write moves the modification time to now
+        file.write_at(0, b"x").unwrap();
+        assert_eq!(file.metadata().unwrap().mtime, fs.current_time());
+    }
+
+    #[test]
+    fn timestamps() {
+        let at = |sec| Timespec { sec, nsec: 0 };
+        let clock = Arc::new(ManualClock::new(at(1)));
+        let fs = JCBFileSystem::create(MemDevice::new(1 << 20), 1 << 20, clock.clone()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let times = |inode: &Arc<dyn Inode>| {
+            let meta = inode.metadata().unwrap();
+            (meta.atime, meta.mtime, meta.ctime)
+        };
+        assert_eq!(times(&root), (at(1), at(1), at(1)));
+
+        clock.set(at(2));
+        let file = root.create("file", FileType::File, 0o644).unwrap();
+        assert_eq!(times(&file), (at(2), at(2), at(2)));
+        assert_eq!(times(&root), (at(1), at(2), at(2)));
+
+        clock.set(at(3));
+        file.write_at(0, b"data").unwrap();
+        assert_eq!(times(&file), (at(2), at(3), at(3)));
+
+        clock.set(at(4));
+        file.read_at(0, &mut [0; 4]).unwrap();
+        assert_eq!(times(&file), (at(4), at(3), at(3)));
+
+        clock.set(at(5));
+        root.link("other", &file).unwrap();
+        assert_eq!(times(&file), (at(4), at(3), at(5)));
+        assert_eq!(times(&root), (at(1), at(5), at(5)));
+
+        clock.set(at(6));
+        let mut meta = file.metadata().unwrap();
+        meta.permission = 0o600;
+        file.set_metadata(&meta).unwrap();
+        assert_eq!(times(&file), (at(4), at(3), at(6)));
+    }
+
+    #[test]
+    fn permission() {
+        let fs = JCBFileSystem::create(MemDevice::new(1 << 20), 1 << 20, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let alice = Credentials::new(1000, 100);
+        let bob = Credentials::new(1001, 100);
+
+        // only root may write the root dir
+        assert_eq!(
+            root.create_as(&alice, "file", FileType::File, 0o644).err(),
+            Some(FsError::PermissionDenied)
+        );
+        let tmp = root.create_as(&Credentials::root(), "tmp", FileType::Dir, 0o1777).unwrap();
+        let file = tmp.create_as(&alice, "file", FileType::File, 0o640).unwrap();
+        let meta = file.metadata().unwrap();
+        assert_eq!((meta.uid, meta.gid), (1000, 100));
+
+        assert_eq!(file.write_at_as(&alice, 0, b"data"), Ok(4));
+        assert_eq!(file.write_at_as(&bob, 0, b"data"), Err(FsError::PermissionDenied));
+        assert_eq!(file.read_at_as(&bob, 0, &mut [0; 4]), Ok(4));
+
+        // the sticky bit keeps bob from removing the file of alice
+        assert_eq!(tmp.unlink_as(&bob, "file"), Err(FsError::PermissionDenied));
+        assert_eq!(tmp.unlink_as(&alice, "file"), Ok(()));
+
+        let mut meta = tmp.metadata().unwrap();
+        meta.permission = 0o700;
+        tmp.set_metadata(&meta).unwrap();
+        assert_eq!(tmp.find_as(&alice, "file").err(), Some(FsError::PermissionDenied));
+        assert_eq!(tmp.find_as(&Credentials::root(), "file").err(), Some(FsError::EntryNotFound));
+    }
+
+    #[test]
+    fn open() {
+        let fs = JCBFileSystem::create(MemDevice::new(1 << 20), 1 << 20, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let cred = Credentials::root();
+        let rw = OpenFlags::READ_WRITE;
+
+        assert_eq!(root.open(&cred, "/dir/file", rw, 0).err(), Some(FsError::EntryNotFound));
+        root.create("dir", FileType::Dir, 0o755).unwrap();
+        let file = root.open(&cred, "/dir/file", rw | OpenFlags::CREATE, 0o644).unwrap();
+        assert_eq!(file.write(b"hello world"), Ok(11));
+        assert_eq!(file.seek(SeekFrom::Start(6)), Ok(6));
+        let mut buf = [0; 16];
+        assert_eq!(file.read(&mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"world");
+        assert_eq!(file.seek(SeekFrom::Current(-5)), Ok(6));
+        assert_eq!(file.seek(SeekFrom::End(-11)), Ok(0));
+        assert_eq!(file.seek(SeekFrom::Current(-1)), Err(FsError::InvalidParam));
+
+        assert_eq!(
+            root.open(&cred, "dir/file", rw | OpenFlags::CREATE | OpenFlags::EXCLUSIVE, 0o644).err(),
+            Some(FsError::EntryExist)
+        );
+        assert_eq!(root.open(&cred, "dir/file", rw | OpenFlags::DIRECTORY, 0).err(), Some(FsError::NotDir));
+        assert_eq!(root.open(&cred, "dir", rw, 0).err(), Some(FsError::IsDir));
+        assert!(root.open(&cred, "dir/", OpenFlags::READ | OpenFlags::DIRECTORY, 0).is_ok());
+        assert_eq!(root.open(&cred, "dir/file/", OpenFlags::READ, 0).err(), Some(FsError::NotDir));
+        assert_eq!(root.open(&cred, "dir/new/", rw | OpenFlags::CREATE, 0o644).err(), Some(FsError::IsDir));
+        assert_eq!(root.open(&cred, "dir/file/new", rw | OpenFlags::CREATE, 0o644).err(), Some(FsError::NotDir));
+        let dir = root.find("dir").unwrap();
+        assert!(dir.open(&cred, "..//dir/./file", OpenFlags::READ, 0).is_ok());
+
+        // a dangling symlink is created at its target, through a chain of them
+        root.create_symlink("dangling", "dir/target").unwrap();
+        root.create_symlink("chain", "/dangling").unwrap();
+        let exclusive = rw | OpenFlags::CREATE | OpenFlags::EXCLUSIVE;
+        assert_eq!(root.open(&cred, "chain", exclusive, 0o644).err(), Some(FsError::EntryExist));
+        let target = root.open(&cred, "chain", rw | OpenFlags::CREATE, 0o644).unwrap();
+        assert_eq!(target.inode().metadata().unwrap().inode_id, dir.find("target").unwrap().metadata().unwrap().inode_id);
+        assert_eq!(root.find("dangling").unwrap().metadata().unwrap().type_, FileType::SymLink);
+        root.create_symlink("nowhere", "missing/target").unwrap();
+        assert_eq!(root.open(&cred, "nowhere", rw | OpenFlags::CREATE, 0o644).err(), Some(FsError::EntryNotFound));
+        root.create_symlink("loop", "loop").unwrap();
+        assert_eq!(root.open(&cred, "loop", rw | OpenFlags::CREATE, 0o644).err(), Some(FsError::SymLoop));
+
+        // appends never overwrite each other, whatever the cursors say
+        let a = root.open(&cred, "dir/file", OpenFlags::WRITE | OpenFlags::APPEND, 0).unwrap();
+        let b = root.open(&cred, "dir/file", OpenFlags::WRITE | OpenFlags::APPEND, 0).unwrap();
+        assert_eq!(a.write(b"!"), Ok(1));
+        assert_eq!(b.write(b"?"), Ok(1));
+        assert_eq!(a.seek(SeekFrom::Current(0)), Ok(12));
+        assert_eq!(file.read(&mut buf), Ok(13));
+        assert_eq!(&buf[..13], b"hello world!?");
+        assert_eq!(a.read(&mut buf), Err(FsError::BadHandle));
+        assert_eq!(file.write(b"x"), Ok(1));
+
+        let file = root.open(&cred, "/dir/file", OpenFlags::WRITE | OpenFlags::TRUNCATE, 0).unwrap();
+        assert_eq!(file.inode().metadata().unwrap().size, 0);
+
+        // the mode applies to everyone but the creator
+        let alice = Credentials::new(1000, 100);
+        let tmp = root.create("tmp", FileType::Dir, 0o777).unwrap();
+        let mine = tmp.open(&alice, "mine", OpenFlags::WRITE | OpenFlags::CREATE, 0o400).unwrap();
+        assert_eq!(mine.write(b"data"), Ok(4));
+        assert_eq!(tmp.open(&alice, "mine", OpenFlags::WRITE, 0).err(), Some(FsError::PermissionDenied));
+    }
+
+    #[test]
+    fn read_dir() {
+        let fs = JCBFileSystem::create(MemDevice::new(4 << 20), 4 << 20, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let dir = root.create("dir", FileType::Dir, 0o755).unwrap();
+        let names: Vec<String> = (0..40).map(|i| format!("file{}", i)).collect();
+        for name in names.iter() {
+            dir.create(name, FileType::File, 0o644).unwrap();
+        }
+        dir.create("sub", FileType::Dir, 0o755).unwrap();
+        fs.sync().unwrap();
+        let cached = fs.cache_inodes.read().len();
+
+        let entries: Vec<DirEntry> = dir.read_dir().collect::<Result<_>>().unwrap();
+        assert_eq!(entries.len(), 2 + 40 + 1);
+        // the types come from the inode table, no inode is loaded
+        assert_eq!(fs.cache_inodes.read().len(), cached);
+        assert_eq!(entries[0].name, ".");
+        assert_eq!(entries[1].inode_id, INO_ROOT);
+        assert_eq!(entries[2].file_type, FileType::File);
+        assert_eq!(entries[42].file_type, FileType::Dir);
+        let (meta, name) = dir.get_entry_with_meta_data(42).unwrap();
+        assert_eq!((meta.inode_id, meta.type_, name.as_str()), (entries[42].inode_id, FileType::Dir, "sub"));
+
+        // stop in the middle, remove entries on both sides of the cursor, then resume
+        let mut cursor = dir.read_dir();
+        let seen: Vec<String> = cursor.by_ref().take(20).map(|entry| entry.unwrap().name).collect();
+        let cookie = cursor.cookie();
+        dir.unlink("file3").unwrap();
+        dir.unlink("file30").unwrap();
+        let rest: Vec<String> = dir.read_dir_from(cookie).map(|entry| entry.unwrap().name).collect();
+        for name in names.iter().filter(|&name| name != "file3" && name != "file30") {
+            let count = seen.iter().chain(rest.iter()).filter(|&seen| seen == name).count();
+            assert_eq!(count, 1, "{}", name);
+        }
+        assert!(!rest.contains(&String::from("file30")));
+
+        // the free slot is taken again
+        let size = dir.metadata().unwrap().size;
+        dir.create("new", FileType::File, 0o644).unwrap();
+        assert_eq!(dir.metadata().unwrap().size, size);
+        assert_eq!(dir.get_entry(5).unwrap(), "new");
+
+        assert_eq!(dir.read_dir_from(1000).count(), 0);
+        let file = dir.find("file0").unwrap();
+        assert!(matches!(file.read_dir().next(), Some(Err(FsError::NotDir))));
+        assert_eq!(file.find(".").err(), Some(FsError::NotDir));
+    }
+}
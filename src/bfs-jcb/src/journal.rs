@@ -0,0 +1,377 @@
+//! Write-ahead journal of the metadata
+//!
+//! The journal stands in for the device wherever metadata is read or written. Writes are kept
+//! in memory as the running transaction and reads see them, so the blocks on the device stay as
+//! they were at the last commit. `commit` writes the transaction to the journal region and seals
+//! it with a commit block, and only then writes the blocks to where they belong. A transaction
+//! that is committed but not yet home is written home again by `replay` when the image is opened.
+//! A transaction is never split: the fs commits early between operations to keep it in the journal.
+//!
+//! layout: | header | descriptor | block ... | commit |
+//!
+//! Every transaction takes the same place right after the header, the header tells the sequence
+//! number of the next one, so a transaction that is home already is never replayed.
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::collections::btree_map::Entry;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use fs_jcb::{BlockIter, DevError, DevErrorKind, Device, FsError, Result, uninit_memory};
+use spin::Mutex;
+
+use crate::DeviceExt;
+use crate::crc::crc32c;
+use crate::structs::{
+    AsBuf, BLKSIZE, BLKSIZE_LOG2, BlockId, JOURNAL_COMMIT_MAGIC, JOURNAL_DESCRIPTOR_MAGIC, JOURNAL_MAGIC,
+    JOURNAL_TARGETS, JournalCommit, JournalDescriptor, JournalHeader,
+};
+
+type Block = Box<[u8; BLKSIZE]>;
+
+pub struct Journal {
+    device: Arc<dyn Device>,
+    /// first block of the journal, the header
+    begin: BlockId,
+    /// number of journal blocks
+    blocks: usize,
+    /// sequence number of the next transaction
+    sequence: Mutex<u32>,
+    /// new content of the blocks written since the last commit
+    running: Mutex<BTreeMap<BlockId, Block>>,
+}
+
+impl Journal {
+    /// set up an empty journal in `blocks` blocks from `begin` on
+    pub fn format(device: Arc<dyn Device>, begin: BlockId, blocks: usize) -> Result<Self> {
+        let header = JournalHeader { magic: JOURNAL_MAGIC, blocks: blocks as u32, sequence: 1, reserved: 0 };
+        // a descriptor left by an earlier image must not be taken for a transaction
+        device.write_block(begin + 1, 0, &[0u8; BLKSIZE])?;
+        device.write_block(begin, 0, header.as_buf())?;
+        device.sync()?;
+        Ok(Self::new(device, begin, blocks, 1))
+    }
+
+    /// open the journal, writing the last transaction home if it is committed but maybe not home
+    ///
+    /// a broken header is reported as `FsError::WrongFs`
+    pub fn replay(device: Arc<dyn Device>, begin: BlockId, blocks: usize) -> Result<Self> {
+        let mut header: JournalHeader = device.load_struct(begin, 0, false)?;
+        if header.magic != JOURNAL_MAGIC || header.blocks as usize != blocks {
+            return Err(FsError::WrongFs);
+        }
+        let journal = Self::new(device, begin, blocks, header.sequence);
+        if let Some(transaction) = journal.read_transaction(header.sequence)? {
+            for (target, block) in transaction.iter() {
+                journal.device.write_block(*target, 0, &block[..])?;
+            }
+            journal.device.sync()?;
+            header.sequence = header.sequence.wrapping_add(1);
+            journal.device.write_block(begin, 0, header.as_buf())?;
+            journal.device.sync()?;
+            *journal.sequence.lock() = header.sequence;
+        }
+        Ok(journal)
+    }
+
+    fn new(device: Arc<dyn Device>, begin: BlockId, blocks: usize, sequence: u32) -> Self {
+        Journal { device, begin, blocks, sequence: Mutex::new(sequence), running: Mutex::new(BTreeMap::new()) }
+    }
+
+    /// max number of blocks in a transaction of a journal of `blocks` blocks
+    pub fn capacity_of(blocks: usize) -> usize {
+        blocks.saturating_sub(3).min(JOURNAL_TARGETS)
+    }
+
+    /// max number of blocks in a transaction
+    pub fn capacity(&self) -> usize {
+        Self::capacity_of(self.blocks)
+    }
+
+    /// number of blocks in the running transaction
+    pub fn running_blocks(&self) -> usize {
+        self.running.lock().len()
+    }
+
+    /// make the running transaction durable and write its blocks home
+    ///
+    /// a transaction is committed as a whole or not at all: one larger than the journal is
+    /// `FsError::NoDeviceSpace`, and the device stays as it was at the last commit
+    pub fn commit(&self) -> Result<()> {
+        let mut running = self.running.lock();
+        if running.len() > self.capacity() {
+            return Err(FsError::NoDeviceSpace);
+        }
+        let blocks: Vec<(&BlockId, &Block)> = running.iter().collect();
+        if !blocks.is_empty() {
+            self.write_transaction(&blocks)?;
+        }
+        // kept until everything is home, so that a failed commit can be tried again
+        running.clear();
+        Ok(())
+    }
+
+    fn write_transaction(&self, blocks: &[(&BlockId, &Block)]) -> Result<()> {
+        let mut sequence = self.sequence.lock();
+        let mut descriptor: JournalDescriptor = unsafe { uninit_memory() };
+        descriptor.magic = JOURNAL_DESCRIPTOR_MAGIC;
+        descriptor.sequence = *sequence;
+        descriptor.count = blocks.len() as u32;
+        descriptor.reserved = 0;
+        descriptor.targets.fill(0);
+        for (i, (target, block)) in blocks.iter().enumerate() {
+            descriptor.targets[i] = **target as u32;
+            self.device.write_block(self.begin + 2 + i, 0, &block[..])?;
+        }
+        let checksum = blocks.iter().fold(crc32c(0, descriptor.as_buf()), |checksum, (_, block)| crc32c(checksum, &block[..]));
+        self.device.write_block(self.begin + 1, 0, descriptor.as_buf())?;
+        self.device.sync()?;
+
+        // the transaction counts from here on
+        let commit = JournalCommit { magic: JOURNAL_COMMIT_MAGIC, sequence: *sequence, count: blocks.len() as u32, checksum };
+        let mut block = [0u8; BLKSIZE];
+        block[..core::mem::size_of::<JournalCommit>()].copy_from_slice(commit.as_buf());
+        self.device.write_block(self.begin + 2 + blocks.len(), 0, &block)?;
+        self.device.sync()?;
+
+        for (target, block) in blocks {
+            self.device.write_block(**target, 0, &block[..])?;
+        }
+        self.device.sync()?;
+
+        // home, never to be replayed
+        *sequence = sequence.wrapping_add(1);
+        let header = JournalHeader { magic: JOURNAL_MAGIC, blocks: self.blocks as u32, sequence: *sequence, reserved: 0 };
+        self.device.write_block(self.begin, 0, header.as_buf())?;
+        self.device.sync()?;
+        Ok(())
+    }
+
+    /// the transaction `sequence` with its blocks, if it is committed and intact
+    fn read_transaction(&self, sequence: u32) -> Result<Option<Vec<(BlockId, Block)>>> {
+        let descriptor: Box<JournalDescriptor> = Box::new(self.device.load_struct(self.begin + 1, 0, false)?);
+        let count = descriptor.count as usize;
+        if descriptor.magic != JOURNAL_DESCRIPTOR_MAGIC || descriptor.sequence != sequence || count == 0 || count > self.capacity() {
+            return Ok(None);
+        }
+        let commit: JournalCommit = self.device.load_struct(self.begin + 2 + count, 0, false)?;
+        if commit.magic != JOURNAL_COMMIT_MAGIC || commit.sequence != sequence || commit.count as usize != count {
+            return Ok(None);
+        }
+        let mut checksum = crc32c(0, descriptor.as_buf());
+        let mut transaction = Vec::with_capacity(count);
+        for (i, &target) in descriptor.targets[..count].iter().enumerate() {
+            let mut block = Box::new([0u8; BLKSIZE]);
+            self.device.read_block(self.begin + 2 + i, 0, &mut block[..])?;
+            checksum = crc32c(checksum, &block[..]);
+            transaction.push((target as BlockId, block));
+        }
+        // torn while being written
+        if checksum != commit.checksum {
+            return Ok(None);
+        }
+        Ok(Some(transaction))
+    }
+}
+
+/// the device as it will be after the next commit
+impl Device for Journal {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> core::result::Result<usize, DevError> {
+        let running = self.running.lock();
+        let mut done = 0;
+        for range in (BlockIter { begin: offset, end: offset + buf.len(), block_size_log2: BLKSIZE_LOG2 }) {
+            let part = &mut buf[done..done + range.len()];
+            match running.get(&range.block) {
+                Some(block) => part.copy_from_slice(&block[range.begin..range.end]),
+                None => {
+                    let len = self.device.read_at(range.block * BLKSIZE + range.begin, part)?;
+                    if len < part.len() {
+                        return Ok(done + len);
+                    }
+                }
+            }
+            done += range.len();
+        }
+        Ok(done)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> core::result::Result<usize, DevError> {
+        let mut running = self.running.lock();
+        let mut done = 0;
+        for range in (BlockIter { begin: offset, end: offset + buf.len(), block_size_log2: BLKSIZE_LOG2 }) {
+            let block = match running.entry(range.block) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => {
+                    let mut block = Box::new([0u8; BLKSIZE]);
+                    if !range.is_full() {
+                        match self.device.read_at(range.block * BLKSIZE, &mut block[..])? {
+                            BLKSIZE => {}
+                            _ if done == 0 => return Err(DevErrorKind::OutOfRange.into()),
+                            _ => return Ok(done),
+                        }
+                    }
+                    entry.insert(block)
+                }
+            };
+            block[range.begin..range.end].copy_from_slice(&buf[done..done + range.len()]);
+            done += range.len();
+        }
+        Ok(done)
+    }
+
+    /// nothing is durable before `commit`
+    fn sync(&self) -> core::result::Result<(), DevError> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{FormatOptions, JCBFileSystem, fsck};
+    use alloc::format;
+    use crate::structs::SuperBlock;
+    use crate::test::{MemDevice, clock};
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use fs_jcb::{FileSystem, FileType};
+
+    /// loses every write after a number of syncs, as if the power went off
+    struct CrashDevice {
+        inner: Arc<MemDevice>,
+        syncs: AtomicUsize,
+    }
+
+    impl Device for CrashDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> core::result::Result<usize, DevError> {
+            self.inner.read_at(offset, buf)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> core::result::Result<usize, DevError> {
+            match self.syncs.load(Ordering::SeqCst) {
+                0 => Ok(buf.len()),
+                _ => self.inner.write_at(offset, buf),
+            }
+        }
+        fn sync(&self) -> core::result::Result<(), DevError> {
+            let _ = self.syncs.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |syncs| syncs.checked_sub(1));
+            Ok(())
+        }
+    }
+
+    const OPTIONS: FormatOptions = FormatOptions { inodes: None, features: 0, journal_blocks: 64 };
+
+    /// an image with a synced dir, and a file in it written up to the `syncs`th sync of the next sync
+    fn crash(syncs: usize) -> Arc<MemDevice> {
+        let inner = MemDevice::new(1 << 20);
+        let device = Arc::new(CrashDevice { inner: inner.clone(), syncs: AtomicUsize::new(usize::MAX) });
+        let fs = JCBFileSystem::create_with(device.clone(), 1 << 20, OPTIONS, clock()).unwrap();
+        let dir = fs.root_inode().unwrap().create("dir", FileType::Dir, 0o755).unwrap();
+        fs.sync().unwrap();
+
+        let file = dir.create("file", FileType::File, 0o644).unwrap();
+        file.write_at(0, b"hello").unwrap();
+        device.syncs.store(syncs, Ordering::SeqCst);
+        fs.sync().unwrap();
+        drop((file, dir, fs));
+        inner
+    }
+
+    fn has_file(device: Arc<MemDevice>) -> bool {
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let dir = fs.root_inode().unwrap().find("dir").unwrap();
+        match dir.find("file") {
+            Ok(file) => {
+                let mut buf = [0; 5];
+                assert_eq!(file.read_at(0, &mut buf), Ok(5));
+                assert_eq!(&buf, b"hello");
+                true
+            }
+            Err(FsError::EntryNotFound) => false,
+            Err(err) => panic!("{:?}", err),
+        }
+    }
+
+    #[test]
+    fn overlay() {
+        let device = MemDevice::new(16 * BLKSIZE);
+        let journal = Arc::new(Journal::format(device.clone(), 0, 8).unwrap());
+        let meta: Arc<dyn Device> = journal.clone();
+        meta.write_block(10, 100, b"new").unwrap();
+        let mut buf = [0; 3];
+        meta.read_block(10, 100, &mut buf).unwrap();
+        assert_eq!(&buf, b"new");
+        // the device sees nothing before the commit
+        assert_eq!(&device.0.lock()[10 * BLKSIZE + 100..][..3], &[0; 3]);
+        journal.commit().unwrap();
+        assert_eq!(&device.0.lock()[10 * BLKSIZE + 100..][..3], b"new");
+        assert!(meta.write_block(16, 0, b"out").is_err());
+    }
+
+    #[test]
+    fn oversized() {
+        let device = MemDevice::new(16 * BLKSIZE);
+        let journal = Arc::new(Journal::format(device.clone(), 0, 8).unwrap());
+        let meta: Arc<dyn Device> = journal.clone();
+        for block in 8..8 + journal.capacity() + 1 {
+            meta.write_block(block, 0, b"new").unwrap();
+        }
+        // never committed in parts
+        assert_eq!(journal.commit(), Err(FsError::NoDeviceSpace));
+        assert!(device.0.lock()[8 * BLKSIZE..].iter().all(|&byte| byte == 0));
+    }
+
+    /// an image with `DIRS` dirs created one by one, more than a transaction holds,
+    /// all of it written up to the `syncs`th sync, and the number of syncs it took
+    fn crash_dirs(syncs: usize) -> (Arc<MemDevice>, usize) {
+        let inner = MemDevice::new(1 << 20);
+        let device = Arc::new(CrashDevice { inner: inner.clone(), syncs: AtomicUsize::new(usize::MAX) });
+        let fs = JCBFileSystem::create_with(device.clone(), 1 << 20, OPTIONS, clock()).unwrap();
+        device.syncs.store(syncs, Ordering::SeqCst);
+        let root = fs.root_inode().unwrap();
+        for i in 0..DIRS {
+            // past the crash nothing is written, what is read back may not make sense
+            let _ = root.create(&format!("{}", i), FileType::Dir, 0o755);
+        }
+        let _ = fs.sync();
+        drop((root, fs));
+        (inner, syncs - device.syncs.load(Ordering::SeqCst))
+    }
+
+    const DIRS: usize = 60;
+
+    #[test]
+    fn large() {
+        let (_, syncs) = crash_dirs(usize::MAX);
+        let mut partial = false;
+        for syncs in 0..syncs {
+            let fs = JCBFileSystem::open(crash_dirs(syncs).0, clock()).unwrap();
+            assert_eq!(fsck::check(&fs, false).unwrap(), []);
+            // the operations committed so far, every one of them whole
+            let root = fs.root_inode().unwrap();
+            let found = (0..DIRS).take_while(|i| root.find(&format!("{}", i)).is_ok()).count();
+            assert!((found..DIRS).all(|i| root.find(&format!("{}", i)).err() == Some(FsError::EntryNotFound)));
+            partial |= found > 0 && found < DIRS;
+        }
+        // committed in more than one transaction
+        assert!(partial);
+    }
+
+    #[test]
+    fn replay() {
+        // the data, the journal blocks and the commit are durable, the blocks never got home
+        assert!(has_file(crash(3)));
+        // every block is home, but the header still asks for the transaction
+        assert!(has_file(crash(4)));
+        // not committed, as if the file was never created
+        assert!(!has_file(crash(2)));
+        assert!(!has_file(crash(0)));
+        assert!(has_file(crash(usize::MAX)));
+    }
+
+    #[test]
+    fn torn() {
+        let device = crash(3);
+        let super_block: SuperBlock = (device.clone() as Arc<dyn Device>).load_struct(0, 0, false).unwrap();
+        // a block of the transaction is not what the commit says
+        device.0.lock()[(super_block.journal as usize + 2) * BLKSIZE] ^= 1;
+        assert!(!has_file(device));
+    }
+}
@@ -1,50 +1,85 @@
-
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
 
 extern crate alloc;
 extern crate fs_jcb;
 
+mod crc;
+mod extent;
+pub mod fsck;
 mod inode_impl;
-mod structs;
+mod journal;
+pub mod structs;
 
-use core::collections::BTreeMap;
-use core::sync::Weak;
-use core::sync::Arc;
 use alloc::collections::BTreeMap;
 use alloc::sync::{Arc, Weak};
+use alloc::vec;
 use alloc::vec::Vec;
-use fs_jcb::{Device, Dirty, uninit_memory};
+use core::mem::{offset_of, size_of};
+use bitvec::order::Lsb0;
+use bitvec::vec::BitVec;
+use fs_jcb::{DevErrorKind, Device, DeviceRegistry, Dirty, FileSystem, FileType, FsError, Inode, Result, TimeProvider, Timespec, make_rdev, uninit_memory};
 use spin::{Mutex, RwLock};
-use crate::bfs::inode_impl::InodeImpl;
-use crate::bfs::structs::{Alloc, AsBuf, BLKSIZE, BlockId, DiskINode, FreeMap, InodeId, SuperBlock};
-use crate::block_device::{BlockDevice, Device};
+use crate::crc::crc32c;
 use crate::inode_impl::InodeImpl;
-use crate::structs::{AsBuf, BLKSIZE, BlockId, DiskINode, FreeMap, InodeId, SuperBlock};
-use crate::util::{Dirty, uninit_memory};
-use crate::fs_jcb::{Result,FileSystem,FileType,Inode,BlockDevice};
+use crate::journal::Journal;
+use crate::structs::{Alloc, AsBuf, BLKBITS, BLKN_FREEMAP, BLKN_SUPER, BLKSIZE, BYTES_PER_INODE, BlockId, CHECKSUMS_PER_BLOCK, DEFAULT_INFO, DiskINode, FEATURES, FEATURE_CHECKSUMS, FEATURE_EXTENTS, FEATURE_JOURNAL, FreeMap, INODES_PER_BLOCK, INODE_CRITICAL, INODE_EXTENTS, INODE_SIZE, INO_ROOT, InodeId, JOURNAL_OP_BLOCKS, MAGIC, MIN_JOURNAL_BLOCKS, Str32, SuperBlock};
 
 trait DeviceExt: Device {
+    /// a short read goes on from where it stopped, so that the error of the device is the one
+    /// reported, reading nothing at all means the block is past the end of the device
     fn read_block(&self, id: BlockId, offset: usize, buf: &mut [u8]) -> Result<()> {
         debug_assert!(offset + buf.len() <= BLKSIZE);
-        match self.read_at(id * BLKSIZE + offset, buf) {
-            Ok(len) if len == buf.len() => Ok(()),
-            _ => panic!("cannot read block {} offset {} from device", id, offset),
+        let mut done = 0;
+        while done < buf.len() {
+            match self.read_at(id * BLKSIZE + offset + done, &mut buf[done..])? {
+                0 => return Err(FsError::DeviceError(DevErrorKind::OutOfRange)),
+                len => done += len,
+            }
         }
+        Ok(())
     }
-    fn write_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> vfs::Result<()> {
+    /// a short write goes on from where it stopped, so that the error of the device is the one
+    /// reported, writing nothing at all means the block is past the end of the device
+    fn write_block(&self, id: BlockId, offset: usize, buf: &[u8]) -> Result<()> {
         debug_assert!(offset + buf.len() <= BLKSIZE);
-        match self.write_at(id * BLKSIZE + offset, buf) {
-            Ok(len) if len == buf.len() => Ok(()),
-            _ => panic!("cannot write block {} offset {} to device", id, offset),
+        let mut done = 0;
+        while done < buf.len() {
+            match self.write_at(id * BLKSIZE + offset + done, &buf[done..])? {
+                0 => return Err(FsError::DeviceError(DevErrorKind::OutOfRange)),
+                len => done += len,
+            }
+        }
+        Ok(())
+    }
+    /// Load struct `T` from `offset` in given block in device,
+    /// with `checksums` a struct carrying a checksum must match it
+    ///
+    /// bytes that make no `T`, say an unknown file type, are `FsError::WrongFs`
+    fn load_struct<T: AsBuf>(&self, id: BlockId, offset: usize, checksums: bool) -> Result<T> {
+        let mut raw = vec![0u8; size_of::<T>()];
+        self.read_block(id, offset, &mut raw)?;
+        if let (true, Some(at)) = (checksums, T::CHECKSUM) {
+            let stored = u32::from_ne_bytes(raw[at..at + 4].try_into().unwrap());
+            if stored != T::checksum_of(&raw) {
+                return Err(FsError::Corrupted);
+            }
+        }
+        if !T::valid(&raw) {
+            return Err(FsError::WrongFs);
         }
+        Ok(unsafe { core::ptr::read_unaligned(raw.as_ptr() as *const T) })
     }
-    /// Load struct `T` from given block in device
-    /// TODO THINK ABOUT IT
-    fn load_struct<T: AsBuf>(&self, id: BlockId) -> vfs::Result<T> {
-        let mut s: T = unsafe { uninit_memory() };
-        self.read_block(id, 0,  s.as_buf_mut())?;
-        Ok(s)
+    /// Store struct `T` at `offset` in given block in device,
+    /// with `checksums` the checksum of a struct carrying one is filled in
+    fn store_struct<T: AsBuf>(&self, id: BlockId, offset: usize, s: &T, checksums: bool) -> Result<()> {
+        match (checksums, T::CHECKSUM) {
+            (true, Some(at)) => {
+                let mut buf = s.as_buf().to_vec();
+                buf[at..at + 4].copy_from_slice(&s.checksum().to_ne_bytes());
+                self.write_block(id, offset, &buf)
+            }
+            _ => self.write_block(id, offset, s.as_buf()),
+        }
     }
 }
 
@@ -52,77 +87,647 @@ impl DeviceExt for dyn Device {}
 
 
 pub struct JCBFileSystem{
+    /// file data goes here directly
     pub device:Arc<dyn Device>,
 
+    /// metadata goes here: the journal if there is one, the device otherwise
+    meta:Arc<dyn Device>,
+
+    journal:Option<Arc<Journal>>,
+
+    /// blocks freed since the last commit, they are not reused before it
+    pending_free:Mutex<Vec<BlockId>>,
+
     cache_inodes: RwLock<BTreeMap<InodeId, Weak<InodeImpl>>>,
 
     free_map:RwLock<FreeMap>,
 
-    super_block:RwLock<Dirty<SuperBlock>>
+    inode_map:RwLock<FreeMap>,
+
+    super_block:RwLock<Dirty<SuperBlock>>,
+
+    /// drivers of the char/block device nodes
+    device_registry:Arc<DeviceRegistry>,
+
+    /// source of every timestamp
+    clock:Arc<dyn TimeProvider>,
+
+    self_ptr:Weak<JCBFileSystem>,
+}
+
+/// where the regions of an image begin
+#[derive(Debug, Eq, PartialEq)]
+struct Layout {
+    free_map_blocks: usize,
+    inode_map: BlockId,
+    inode_map_blocks: usize,
+    inode_table: BlockId,
+    journal: BlockId,
+    checksum_table: BlockId,
+    data: BlockId,
 }
 
+impl Layout {
+    fn new(blocks: usize, inodes: usize, journal_blocks: usize, checksums: bool) -> Self {
+        let free_map_blocks = blocks.div_ceil(BLKBITS);
+        let inode_map = BLKN_FREEMAP + free_map_blocks;
+        let inode_map_blocks = inodes.div_ceil(BLKBITS);
+        let inode_table = inode_map + inode_map_blocks;
+        let journal = inode_table + inodes.div_ceil(INODES_PER_BLOCK);
+        let checksum_table = journal + journal_blocks;
+        let checksum_blocks = match checksums {
+            true => blocks.div_ceil(CHECKSUMS_PER_BLOCK),
+            false => 0,
+        };
+        Layout {
+            free_map_blocks,
+            inode_map,
+            inode_map_blocks,
+            inode_table,
+            journal,
+            checksum_table,
+            data: checksum_table + checksum_blocks,
+        }
+    }
+}
+
+/// How `create_with` formats an image
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct FormatOptions {
+    /// number of inodes, one per `BYTES_PER_INODE` bytes if not given
+    pub inodes: Option<usize>,
+    /// `FEATURE_*` bits of the image
+    pub features: u32,
+    /// size of the journal in blocks, no journal if 0
+    pub journal_blocks: usize,
+}
+
+/// Usage of the blocks and inodes of a file system
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Stats {
+    /// number of blocks, metadata included
+    pub blocks: usize,
+    /// number of unused blocks
+    pub free_blocks: usize,
+    /// number of inodes
+    pub inodes: usize,
+    /// number of unused inodes
+    pub free_inodes: usize,
+}
+
+/// `(name, major, minor)` of the nodes created by `create_dev_nodes`, numbered as on linux
+pub const DEV_NODES: &[(&str, usize, usize)] = &[("null", 1, 3), ("zero", 1, 5), ("console", 5, 1)];
+
 impl FileSystem for JCBFileSystem{
 
-    fn root_inode(&self) -> Arc<dyn Inode> {
-        None
+    /// read from the device again unless it is in use, which may fail
+    fn root_inode(&self) -> Result<Arc<dyn Inode>> {
+        Ok(self.get_inode(INO_ROOT)?)
+    }
+
+    /// write back every dirty inode, the maps and the super block,
+    /// with a journal all of them are committed as one transaction
+    fn sync(&self) -> Result<()> {
+        let inodes:Vec<Arc<InodeImpl>>={
+            // the inodes gone since the last sync leave their slots
+            let mut cache_inodes=self.cache_inodes.write();
+            cache_inodes.retain(|_,inode| inode.strong_count()>0);
+            cache_inodes.values().filter_map(|inode| inode.upgrade()).collect()
+        };
+        for inode in inodes{
+            inode.sync_disk_inode()?;
+        }
+        self.sync_meta()?;
+        // the data first, the metadata pointing to it may be replayed after a crash
+        self.device.sync()?;
+        match &self.journal {
+            Some(journal) => journal.commit(),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for JCBFileSystem{
+    /// every inode is gone at this point, only the maps and the super block are left
+    fn drop(&mut self){
+        if let Err(err)=self.sync() {
+            // nobody to report to: the changes are lost, but the kernel goes on
+            log::error!("bfs: failed to sync the file system when dropping: {}", err);
+            self.super_block.write().sync();
+            self.free_map.write().sync();
+            self.inode_map.write().sync();
+        }
     }
 }
 impl JCBFileSystem{
+    /// format the first `space` bytes of the device with the default options
+    ///
+    /// every timestamp of the fs comes from `clock`
     pub fn create(
         block_device:Arc<dyn Device>,
+        space:usize,
+        clock:Arc<dyn TimeProvider>,
+    ) ->Result<Arc<Self>>{
+        Self::create_with(block_device,space,FormatOptions::default(),clock)
+    }
+
+    /// format the first `space` bytes of the device,
+    /// the inode table is rounded up to fill its blocks
+    ///
+    /// layout: | super block | free map ... | inode map ... | inode table ... | journal ... | checksum table ... | data ... |
+    pub fn create_with(
+        block_device:Arc<dyn Device>,
+        space:usize,
+        options:FormatOptions,
+        clock:Arc<dyn TimeProvider>,
+    ) ->Result<Arc<Self>>{
+        let mut features=options.features;
+        if options.journal_blocks>0 {
+            features|=FEATURE_JOURNAL;
+        }
+        if features & !FEATURES != 0
+            || (features & FEATURE_JOURNAL != 0 && options.journal_blocks<MIN_JOURNAL_BLOCKS) {
+            return Err(FsError::InvalidParam);
+        }
+        let blocks=(space/BLKSIZE).min(u32::MAX as usize);
+        let inodes=options.inodes.unwrap_or(space/BYTES_PER_INODE);
+        // slot 0 and the root dir take the first two slots
+        let inodes=(inodes+2).next_multiple_of(INODES_PER_BLOCK).min(u32::MAX as usize/INODES_PER_BLOCK*INODES_PER_BLOCK);
+        let checksums=features & FEATURE_CHECKSUMS != 0;
+        let layout=Layout::new(blocks,inodes,options.journal_blocks,checksums);
+        // at least one data block is needed by the root dir
+        if blocks<=layout.data {
+            return Err(FsError::NoDeviceSpace);
+        }
+        // a transaction holds an operation on the namespace besides the super block and the maps
+        if options.journal_blocks>0
+            && Journal::capacity_of(options.journal_blocks)<1+layout.free_map_blocks+layout.inode_map_blocks+JOURNAL_OP_BLOCKS {
+            return Err(FsError::InvalidParam);
+        }
+
+        let super_block=SuperBlock{
+            magic: MAGIC,
+            blocks: blocks as u32,
+            unused_blocks: (blocks-layout.data) as u32,
+            info: Str32::from(DEFAULT_INFO),
+            free_map_blocks: layout.free_map_blocks as u32,
+            inodes: inodes as u32,
+            unused_inodes: (inodes-2) as u32,
+            inode_map: layout.inode_map as u32,
+            inode_table: layout.inode_table as u32,
+            features,
+            journal: if options.journal_blocks>0 { layout.journal as u32 } else { 0 },
+            journal_blocks: options.journal_blocks as u32,
+            checksum_table: if checksums { layout.checksum_table as u32 } else { 0 },
+            // filled in when it is written
+            checksum: 0,
+        };
+        // keep the bit vectors as long as their blocks, so that they can be written as a whole
+        let mut free_map=BitVec::repeat(false,layout.free_map_blocks*BLKBITS);
+        free_map[layout.data..blocks].fill(true);
+        let mut inode_map=BitVec::repeat(false,layout.inode_map_blocks*BLKBITS);
+        inode_map[INO_ROOT+1..inodes].fill(true);
+        // whatever was on the device before must not be taken for an inode or a checksum
+        for block in (layout.inode_table..layout.journal).chain(layout.checksum_table..layout.data) {
+            block_device.write_block(block,0,&[0u8;BLKSIZE])?;
+        }
+        let journal=match options.journal_blocks {
+            0 => None,
+            n => Some(Arc::new(Journal::format(block_device.clone(),layout.journal,n)?)),
+        };
 
-    ) ->Arc<Mutex<Self>>{
+        let fs=JCBFileSystem{
+            meta: journal.clone().map_or(block_device.clone(),|journal| journal as Arc<dyn Device>),
+            journal,
+            pending_free: Mutex::new(Vec::new()),
+            device: block_device,
+            cache_inodes: RwLock::new(BTreeMap::new()),
+            free_map: RwLock::new(Dirty::new_dirty(free_map)),
+            inode_map: RwLock::new(Dirty::new_dirty(inode_map)),
+            super_block: RwLock::new(Dirty::new_dirty(super_block)),
+            device_registry: Arc::new(DeviceRegistry::new()),
+            clock,
+            self_ptr: Weak::default(),
+        }.wrap();
 
+        // "." and ".." of the root dir both point to itself
+        let root=fs._new_inode(INO_ROOT,fs.stamp(DiskINode::new_dir()));
+        root.init_dir_entry(INO_ROOT)?;
+        root.disk_inode.write().nlinks=2;
+
+        fs.sync()?;
+        Ok(fs)
     }
 
+    /// mount an image created by `create`
+    ///
+    /// anything inconsistent on the disk is reported as `FsError::WrongFs`
     pub fn open(
-        block_device:Arc<dyn Device>
-    )->Arc<Mutex<Self>>{
+        block_device:Arc<dyn Device>,
+        clock:Arc<dyn TimeProvider>,
+    )->Result<Arc<Self>>{
+        // an image shorter than it says is broken, a failing device is reported as it is
+        let read_exact=|offset:usize,buf:&mut [u8]| -> Result<()> {
+            match block_device.read_at(offset,buf)? {
+                len if len==buf.len() => Ok(()),
+                _ => Err(FsError::WrongFs),
+            }
+        };
 
+        let mut super_block:SuperBlock=unsafe{ uninit_memory() };
+        read_exact(BLKN_SUPER*BLKSIZE,super_block.as_buf_mut())?;
+        if super_block.magic!=MAGIC || super_block.features & !FEATURES != 0 {
+            return Err(FsError::WrongFs);
+        }
+        let journal_blocks=super_block.journal_blocks as usize;
+        let journal=if super_block.features & FEATURE_JOURNAL != 0 {
+            let layout=Layout::new(super_block.blocks as usize,super_block.inodes as usize,journal_blocks,false);
+            if super_block.journal as usize!=layout.journal
+                || journal_blocks<MIN_JOURNAL_BLOCKS
+                || layout.data>super_block.blocks as usize {
+                return Err(FsError::WrongFs);
+            }
+            // finish the last transaction committed, it may have updated the super block as well
+            let journal=Journal::replay(block_device.clone(),layout.journal,journal_blocks)?;
+            read_exact(BLKN_SUPER*BLKSIZE,super_block.as_buf_mut())?;
+            Some(Arc::new(journal))
+        }else{
+            None
+        };
+        let blocks=super_block.blocks as usize;
+        let inodes=super_block.inodes as usize;
+        let checksums=super_block.features & FEATURE_CHECKSUMS != 0;
+        let layout=Layout::new(blocks,inodes,journal_blocks,checksums);
+        if checksums && u32::from_ne_bytes(super_block.as_buf()[offset_of!(SuperBlock,checksum)..][..4].try_into().unwrap())!=super_block.checksum() {
+            return Err(FsError::Corrupted);
+        }
+        if super_block.magic!=MAGIC
+            || super_block.features & !FEATURES != 0
+            || (super_block.features & FEATURE_JOURNAL != 0) != journal.is_some()
+            || (journal.is_none() && (super_block.journal!=0 || journal_blocks!=0))
+            || (journal.is_some() && super_block.journal as usize!=layout.journal)
+            || super_block.checksum_table as usize!=if checksums { layout.checksum_table } else { 0 }
+            || super_block.free_map_blocks as usize!=layout.free_map_blocks
+            || super_block.inode_map as usize!=layout.inode_map
+            || super_block.inode_table as usize!=layout.inode_table
+            || !inodes.is_multiple_of(INODES_PER_BLOCK)
+            || inodes<=INO_ROOT
+            || blocks<=layout.data
+            || super_block.unused_blocks as usize>blocks-layout.data
+            || super_block.unused_inodes as usize>inodes-INO_ROOT-1 {
+            return Err(FsError::WrongFs);
+        }
+        // the device must be large enough to hold every block
+        read_exact(blocks*BLKSIZE-1,&mut [0u8])?;
 
+        let read_map=|begin:BlockId,len:usize| -> Result<BitVec<u8,Lsb0>> {
+            let mut raw=vec![0u8;len*BLKSIZE];
+            read_exact(begin*BLKSIZE,&mut raw)?;
+            Ok(BitVec::from_vec(raw))
+        };
+        let free_map=read_map(BLKN_FREEMAP,layout.free_map_blocks)?;
+        // reserved blocks and blocks beyond the end are never free
+        if free_map[..layout.data].any()
+            || free_map[blocks..].any()
+            || free_map.count_ones()!=super_block.unused_blocks as usize {
+            return Err(FsError::WrongFs);
+        }
+        let inode_map=read_map(layout.inode_map,layout.inode_map_blocks)?;
+        // neither is slot 0, the root dir or a slot beyond the table
+        if inode_map[..=INO_ROOT].any()
+            || inode_map[inodes..].any()
+            || inode_map.count_ones()!=super_block.unused_inodes as usize {
+            return Err(FsError::WrongFs);
+        }
+
+        Ok(JCBFileSystem{
+            meta: journal.clone().map_or(block_device.clone(),|journal| journal as Arc<dyn Device>),
+            journal,
+            pending_free: Mutex::new(Vec::new()),
+            device: block_device,
+            cache_inodes: RwLock::new(BTreeMap::new()),
+            free_map: RwLock::new(Dirty::new(free_map)),
+            inode_map: RwLock::new(Dirty::new(inode_map)),
+            super_block: RwLock::new(Dirty::new(super_block)),
+            device_registry: Arc::new(DeviceRegistry::new()),
+            clock,
+            self_ptr: Weak::default(),
+        }.wrap())
+    }
+
+    /// register the drivers of the device nodes here
+    pub fn device_registry(&self)->&Arc<DeviceRegistry>{
+        &self.device_registry
+    }
+
+    /// populate `/dev` of a root image with the nodes the kernel expects
+    ///
+    /// the drivers are registered at runtime, see `device_registry`
+    pub fn create_dev_nodes(&self)->Result<()>{
+        let root=self.root_inode()?;
+        let dev=match root.find("dev") {
+            Ok(dev) => dev,
+            Err(FsError::EntryNotFound) => root.create("dev",FileType::Dir,0o755)?,
+            Err(e) => return Err(e),
+        };
+        for &(name,major,minor) in DEV_NODES {
+            match dev.mknod(name,FileType::CharDevice,0o666,make_rdev(major,minor)) {
+                Ok(_) | Err(FsError::EntryExist) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+
+    /// usage of the blocks and inodes, the reserved inode slot 0 left out
+    pub fn stats(&self)->Stats{
+        let super_block=self.super_block.read();
+        Stats{
+            blocks: super_block.blocks as usize,
+            free_blocks: super_block.unused_blocks as usize,
+            inodes: super_block.inodes as usize-1,
+            free_inodes: super_block.unused_inodes as usize,
+        }
+    }
+
+    /// now, according to the clock of the fs
+    pub fn current_time(&self)->Timespec{
+        self.clock.current_time()
+    }
+
+    fn wrap(self) -> Arc<Self> {
+        // Create an Arc, make a Weak from it, then put it into the struct.
+        // It's a little tricky.
+        let fs = Arc::new(self);
+        let weak = Arc::downgrade(&fs);
+        let ptr = Arc::into_raw(fs) as *mut Self;
+        unsafe {
+            (*ptr).self_ptr = weak;
+            Arc::from_raw(ptr)
+        }
+    }
+
+    /// write super block and the maps back to the device
+    fn sync_meta(&self)->Result<()>{
+        // the blocks freed in this transaction may be reused in the next one
+        let pending_free=core::mem::take(&mut *self.pending_free.lock());
+        for block_id in pending_free {
+            self.dealloc_block(block_id)?;
+        }
+        let mut super_block=self.super_block.write();
+        if super_block.dirty(){
+            let checksums=super_block.features & FEATURE_CHECKSUMS != 0;
+            self.meta.store_struct(BLKN_SUPER,0,&**super_block,checksums)?;
+            super_block.sync();
+        }
+        let mut free_map=self.free_map.write();
+        if free_map.dirty(){
+            for (i,block) in free_map.as_raw_slice().chunks(BLKSIZE).enumerate(){
+                self.meta.write_block(BLKN_FREEMAP+i,0,block)?;
+            }
+            free_map.sync();
+        }
+        let mut inode_map=self.inode_map.write();
+        if inode_map.dirty(){
+            let begin=super_block.inode_map as usize;
+            for (i,block) in inode_map.as_raw_slice().chunks(BLKSIZE).enumerate(){
+                self.meta.write_block(begin+i,0,block)?;
+            }
+            inode_map.sync();
+        }
+        Ok(())
     }
 
-    pub fn alloc_block(&self)->Option<BlockId>{
+    /// take a free block, the one at `hint` or the next free one after it if given,
+    /// so that a file growing block by block stays in one piece
+    pub fn alloc_block(&self,hint:Option<BlockId>)->Option<BlockId>{
         let mut free_map=self.free_map.write();
-        if let Some(id)=free_map.alloc(){
+        if let Some(id)=free_map.alloc_near(hint.unwrap_or(0)){
             let mut super_block=self.super_block.write();
             if super_block.unused_blocks == 0 {
-                free_map[id]=true;
+                free_map.set(id,true);
                 return None;
             }
             super_block.unused_blocks-=1;
             Some(id)
         }else{
-            /// TODO EXCEPTION
+            // TODO EXCEPTION
 
 
             None
         }
     }
 
-    pub fn get_inode(&self,inode_id:InodeId)->Arc<InodeImpl>{
+    /// give the block back to the free map, with a journal only once the transaction
+    /// freeing it is committed, until then a crash may bring back the metadata using it
+    pub fn free_block(&self,block_id:BlockId)->Result<()>{
+        if self.journal.is_some() {
+            self.pending_free.lock().push(block_id);
+            return Ok(());
+        }
+        self.dealloc_block(block_id)
+    }
+
+    fn dealloc_block(&self,block_id:BlockId)->Result<()>{
+        let mut free_map=self.free_map.write();
+        free_map.dealloc(block_id)?;
+        self.super_block.write().unused_blocks+=1;
+        Ok(())
+    }
+
+    /// take an inode slot from the inode map
+    fn alloc_inode(&self)->Option<InodeId>{
+        let mut inode_map=self.inode_map.write();
+        let id=inode_map.alloc()?;
+        let mut super_block=self.super_block.write();
+        if super_block.unused_inodes == 0 {
+            inode_map.set(id,true);
+            return None;
+        }
+        super_block.unused_inodes-=1;
+        Some(id)
+    }
+
+    /// give the inode slot back to the inode map
+    pub fn free_inode(&self,inode_id:InodeId)->Result<()>{
+        let mut inode_map=self.inode_map.write();
+        inode_map.dealloc(inode_id)?;
+        self.super_block.write().unused_inodes+=1;
+        Ok(())
+    }
+
+    /// the content of dirs, symlinks and critical files is metadata, and journaled
+    fn data_is_meta(disk_inode:&DiskINode)->bool{
+        match disk_inode.type_ {
+            FileType::Dir | FileType::SymLink => true,
+            _ => disk_inode.flags & INODE_CRITICAL != 0,
+        }
+    }
+
+    /// where the data of a file goes
+    fn data_device(&self,disk_inode:&DiskINode)->&Arc<dyn Device>{
+        match Self::data_is_meta(disk_inode) {
+            true => &self.meta,
+            false => &self.device,
+        }
+    }
+
+    /// an upper bound of the blocks a sync adds to the running transaction with `cached` inodes
+    /// in the cache: the super block, the maps and the blocks of the inode table holding them
+    fn sync_blocks(&self,cached:usize)->usize{
+        let super_block=self.super_block.read();
+        let inode_table_blocks=(super_block.inodes as usize).div_ceil(INODES_PER_BLOCK);
+        1+super_block.free_map_blocks as usize+(super_block.inodes as usize).div_ceil(BLKBITS)+cached.min(inode_table_blocks)
+    }
+
+    /// make room in the running transaction for an operation writing at most `blocks` blocks
+    /// of metadata, the inodes it changes included
+    ///
+    /// the operations so far are committed first if it would not fit, so that a transaction never
+    /// outgrows the journal, an operation too large for any transaction is `FsError::NoDeviceSpace`.
+    /// to be called before the operation changes anything, with no inode locked
+    pub(crate) fn reserve(&self,blocks:usize)->Result<()>{
+        let Some(journal)=&self.journal else {
+            return Ok(());
+        };
+        let cached=self.cache_inodes.read().len();
+        if journal.running_blocks()+self.sync_blocks(cached)+blocks<=journal.capacity() {
+            return Ok(());
+        }
+        // every inode is clean afterwards, the ones the operation changes are in `blocks`
+        self.sync()?;
+        if self.sync_blocks(0)+blocks>journal.capacity() {
+            return Err(FsError::NoDeviceSpace);
+        }
+        Ok(())
+    }
+
+    /// the image carries checksums
+    fn checksums(&self)->bool{
+        self.super_block.read().features & FEATURE_CHECKSUMS != 0
+    }
+
+    /// block and offset of the checksum of a block in the checksum table
+    fn checksum_slot(&self,block_id:BlockId)->(BlockId,usize){
+        let checksum_table=self.super_block.read().checksum_table as usize;
+        (checksum_table+block_id/CHECKSUMS_PER_BLOCK,block_id%CHECKSUMS_PER_BLOCK*size_of::<u32>())
+    }
+
+    fn set_block_checksum(&self,block_id:BlockId,checksum:u32)->Result<()>{
+        let (block,offset)=self.checksum_slot(block_id);
+        self.meta.write_block(block,offset,checksum.as_buf())
+    }
+
+    /// a whole block of a dir or a critical file, it must match its checksum
+    fn read_checked_block(&self,block_id:BlockId,buf:&mut [u8])->Result<()>{
+        debug_assert_eq!(buf.len(),BLKSIZE);
+        self.meta.read_block(block_id,0,buf)?;
+        let (block,offset)=self.checksum_slot(block_id);
+        let mut checksum=0u32;
+        self.meta.read_block(block,offset,checksum.as_buf_mut())?;
+        if crc32c(0,buf)!=checksum {
+            return Err(FsError::Corrupted);
+        }
+        Ok(())
+    }
+
+    /// write a whole block of a dir or a critical file, and its checksum
+    fn write_checked_block(&self,block_id:BlockId,buf:&[u8])->Result<()>{
+        debug_assert_eq!(buf.len(),BLKSIZE);
+        self.meta.write_block(block_id,0,buf)?;
+        self.set_block_checksum(block_id,crc32c(0,buf))
+    }
+
+    /// mark a file critical for good: with `FEATURE_CHECKSUMS` its data blocks carry checksums
+    /// like the ones of dirs, and go through the journal with the metadata
+    pub fn mark_critical(&self,inode:&Arc<dyn Inode>)->Result<()>{
+        let inode=inode.downcast_ref::<InodeImpl>().ok_or(FsError::NotSameFs)?;
+        if !core::ptr::eq(&*inode.fs,self) {
+            return Err(FsError::NotSameFs);
+        }
+        inode.mark_critical()
+    }
+
+    /// block and offset of the slot of an inode in the inode table
+    fn inode_slot(&self,inode_id:InodeId)->(BlockId,usize){
+        let inode_table=self.super_block.read().inode_table as usize;
+        (inode_table+inode_id/INODES_PER_BLOCK,inode_id%INODES_PER_BLOCK*INODE_SIZE)
+    }
+
+    /// write a disk inode to its slot in the inode table
+    fn write_disk_inode(&self,inode_id:InodeId,disk_inode:&DiskINode)->Result<()>{
+        let (block,offset)=self.inode_slot(inode_id);
+        self.meta.store_struct(block,offset,disk_inode,self.checksums())
+    }
+
+    pub fn get_inode(&self,inode_id:InodeId)->Result<Arc<InodeImpl>>{
         if let Some(inode)=self.cache_inodes.read().get(&inode_id){
             if let Some(inode)=inode.upgrade(){
-                return inode
+                return Ok(inode)
             }
         }
+        if inode_id==0 || inode_id>=self.super_block.read().inodes as usize {
+            return Err(FsError::InvalidParam);
+        }
         // func get_inode only can be called just after getting the inodeId determined in the disk,so the inode must exist
         // no inode or no Arc
-        let disk_inode = Dirty::new(self.device.load_struct::<DiskINode>(inode_id).unwrap());
-        self._new_inode(inode_id,disk_inode)
+        let (block,offset)=self.inode_slot(inode_id);
+        let disk_inode = Dirty::new(self.meta.load_struct::<DiskINode>(block,offset,self.checksums())?);
+        Ok(self._new_inode(inode_id,disk_inode))
+    }
+
+    /// the type of an inode, read from its slot if it is not loaded,
+    /// listing a dir does not load every inode in it
+    pub(crate) fn file_type(&self,inode_id:InodeId)->Result<FileType>{
+        if let Some(inode)=self.cache_inodes.read().get(&inode_id).and_then(Weak::upgrade){
+            return Ok(inode.disk_inode.read().type_);
+        }
+        if inode_id==0 || inode_id>=self.super_block.read().inodes as usize {
+            return Err(FsError::InvalidParam);
+        }
+        let (block,offset)=self.inode_slot(inode_id);
+        Ok(self.meta.load_struct::<DiskINode>(block,offset,self.checksums())?.type_)
     }
 
-    pub fn new_inode_file(&self)->vfs::Result<Arc<InodeImpl>>{
-        let id=self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let inode=self._new_inode(id,Dirty::new_dirty(DiskINode::new_file()));
+    pub fn new_inode_file(&self)->Result<Arc<InodeImpl>>{
+        let id=self.alloc_inode().ok_or(FsError::NoDeviceSpace)?;
+        let inode=self._new_inode(id,self.stamp(DiskINode::new_file()));
         Ok(inode)
     }
-    pub fn new_inode_dir(&self,parent:InodeId)->vfs::Result<Arc<InodeImpl>>{
-        let id=self.alloc_block().ok_or(FsError::NoDeviceSpace)?;
-        let inode=self._new_inode(id,Dirty::new_dirty(DiskINode::new_dir()));
-        inode.init_dir_entry(parent);
+    pub fn new_inode_symlink(&self)->Result<Arc<InodeImpl>>{
+        let id=self.alloc_inode().ok_or(FsError::NoDeviceSpace)?;
+        let inode=self._new_inode(id,self.stamp(DiskINode::new_symlink()));
         Ok(inode)
     }
+    pub fn new_inode_device(&self,type_:FileType,rdev:usize)->Result<Arc<InodeImpl>>{
+        let disk_inode=match type_ {
+            FileType::CharDevice => DiskINode::new_chardevice(rdev),
+            FileType::BlockDevice => DiskINode::new_blockdevice(rdev),
+            _ => return Err(FsError::InvalidParam),
+        };
+        let id=self.alloc_inode().ok_or(FsError::NoDeviceSpace)?;
+        let inode=self._new_inode(id,self.stamp(disk_inode));
+        Ok(inode)
+    }
+    pub fn new_inode_dir(&self,parent:InodeId)->Result<Arc<InodeImpl>>{
+        let id=self.alloc_inode().ok_or(FsError::NoDeviceSpace)?;
+        let inode=self._new_inode(id,self.stamp(DiskINode::new_dir()));
+        inode.init_dir_entry(parent)?;
+        Ok(inode)
+    }
+
+    /// a brand new inode is accessed, modified and changed right now,
+    /// and maps its blocks with extents if the image does
+    fn stamp(&self,mut disk_inode:DiskINode)->Dirty<DiskINode>{
+        if self.super_block.read().features & FEATURE_EXTENTS != 0 {
+            disk_inode.flags|=INODE_EXTENTS;
+        }
+        let now=self.current_time().into();
+        disk_inode.atime=now;
+        disk_inode.mtime=now;
+        disk_inode.ctime=now;
+        Dirty::new_dirty(disk_inode)
+    }
 
     pub fn _new_inode(&self,id:InodeId,disk_inode:Dirty<DiskINode>)->Arc<InodeImpl>{
         let device_inode_id = disk_inode.device_inode_id;
@@ -138,3 +743,386 @@ impl JCBFileSystem{
     }
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use core::mem::size_of;
+    use core::sync::atomic::{AtomicBool, Ordering};
+    use fs_jcb::{DevError, ManualClock};
+    use crate::structs::{DIRENT_SIZE, Str256};
+
+    /// a fixed clock, so that images are reproducible
+    pub fn clock() -> Arc<dyn TimeProvider> {
+        Arc::new(ManualClock::new(Timespec { sec: 0, nsec: 0 }))
+    }
+
+    /// a device backed by memory
+    pub struct MemDevice(pub Mutex<Vec<u8>>);
+
+    impl MemDevice {
+        pub fn new(size: usize) -> Arc<Self> {
+            Arc::new(MemDevice(Mutex::new(vec![0; size])))
+        }
+    }
+
+    impl Device for MemDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> core::result::Result<usize, DevError> {
+            let data = self.0.lock();
+            let offset = offset.min(data.len());
+            let len = buf.len().min(data.len() - offset);
+            buf[..len].copy_from_slice(&data[offset..offset + len]);
+            Ok(len)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> core::result::Result<usize, DevError> {
+            let mut data = self.0.lock();
+            let offset = offset.min(data.len());
+            let len = buf.len().min(data.len() - offset);
+            data[offset..offset + len].copy_from_slice(&buf[..len]);
+            Ok(len)
+        }
+        fn sync(&self) -> core::result::Result<(), DevError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn create() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+
+        let super_block = (device.clone() as Arc<dyn Device>).load_struct::<SuperBlock>(BLKN_SUPER, 0, false).unwrap();
+        assert_eq!(super_block.magic, MAGIC);
+        assert_eq!(super_block.blocks, 256);
+        assert_eq!(super_block.free_map_blocks, 1);
+        // 64 inodes and the 2 reserved ones fill 5 blocks of the table
+        assert_eq!((super_block.inodes, super_block.unused_inodes), (80, 78));
+        assert_eq!((super_block.inode_map, super_block.inode_table), (2, 3));
+        // super block, free map, inode map, inode table and the root dir data are used
+        assert_eq!(super_block.unused_blocks, 256 - 9);
+
+        let root = fs.root_inode().unwrap();
+        assert_eq!(root.get_entry(0).unwrap(), ".");
+        assert_eq!(root.get_entry(1).unwrap(), "..");
+        assert!(root.get_entry(2).is_err());
+    }
+
+    #[test]
+    fn create_too_small() {
+        let device = MemDevice::new(3 * BLKSIZE);
+        assert!(matches!(
+            JCBFileSystem::create(device, 3 * BLKSIZE, clock()),
+            Err(FsError::NoDeviceSpace)
+        ));
+    }
+
+    #[test]
+    fn open() {
+        let device = MemDevice::new(1 << 20);
+        drop(JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap());
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        assert_eq!(root.get_entry(0).unwrap(), ".");
+        assert_eq!(root.get_entry(1).unwrap(), "..");
+        assert_eq!(fs.stats(), Stats { blocks: 256, free_blocks: 256 - 9, inodes: 79, free_inodes: 78 });
+    }
+
+    #[test]
+    fn journal() {
+        let device = MemDevice::new(1 << 20);
+        let options = FormatOptions { journal_blocks: 2, ..FormatOptions::default() };
+        assert_eq!(JCBFileSystem::create_with(device.clone(), 1 << 20, options, clock()).err(), Some(FsError::InvalidParam));
+        let options = FormatOptions { features: FEATURE_JOURNAL, ..FormatOptions::default() };
+        assert_eq!(JCBFileSystem::create_with(device.clone(), 1 << 20, options, clock()).err(), Some(FsError::InvalidParam));
+
+        // too small for an operation on the namespace
+        let options = FormatOptions { journal_blocks: 16, ..FormatOptions::default() };
+        assert_eq!(JCBFileSystem::create_with(device.clone(), 1 << 20, options, clock()).err(), Some(FsError::InvalidParam));
+
+        let options = FormatOptions { journal_blocks: 64, ..FormatOptions::default() };
+        let fs = JCBFileSystem::create_with(device.clone(), 1 << 20, options, clock()).unwrap();
+        // the journal is reserved after the inode table
+        assert_eq!(fs.stats().free_blocks, 256 - 9 - 64);
+        let file = fs.root_inode().unwrap().create("file", FileType::File, 0o644).unwrap();
+        file.write_at(0, b"journaled").unwrap();
+        file.sync_all().unwrap();
+        drop((file, fs));
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let file = fs.root_inode().unwrap().find("file").unwrap();
+        let mut buf = [0; 9];
+        assert_eq!(file.read_at(0, &mut buf), Ok(9));
+        assert_eq!(&buf, b"journaled");
+        drop((file, fs));
+
+        // the data of a critical file goes to the journal, in steps that fit into it
+        let options = FormatOptions { features: FEATURE_CHECKSUMS, journal_blocks: 64, ..FormatOptions::default() };
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create_with(device.clone(), 1 << 20, options, clock()).unwrap();
+        let file = fs.root_inode().unwrap().create("file", FileType::File, 0o644).unwrap();
+        fs.mark_critical(&file).unwrap();
+        let data: Vec<u8> = (0..100 * BLKSIZE).map(|i| i as u8).collect();
+        assert_eq!(file.write_at(1, &data), Ok(data.len()));
+        drop((file, fs));
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let mut buf = vec![0; data.len()];
+        assert_eq!(fs.root_inode().unwrap().find("file").unwrap().read_at(1, &mut buf), Ok(data.len()));
+        assert!(buf == data);
+    }
+
+    #[test]
+    fn checksums() {
+        let options = FormatOptions { features: FEATURE_CHECKSUMS, ..FormatOptions::default() };
+        let fs = JCBFileSystem::create(MemDevice::new(1 << 20), 1 << 20, clock()).unwrap();
+        let file = fs.root_inode().unwrap().create("file", FileType::File, 0o644).unwrap();
+        assert_eq!(fs.mark_critical(&file), Err(FsError::NotSupported));
+        drop((file, fs));
+
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create_with(device.clone(), 1 << 20, options, clock()).unwrap();
+        // one block of the table covers the 256 blocks
+        assert_eq!(fs.stats().free_blocks, 256 - 10);
+        let root = fs.root_inode().unwrap();
+        let critical = root.create("critical", FileType::File, 0o644).unwrap();
+        critical.write_at(0, b"before").unwrap();
+        fs.mark_critical(&critical).unwrap();
+        critical.write_at(BLKSIZE, b"after").unwrap();
+        assert_eq!(fs.mark_critical(&root), Err(FsError::NotFile));
+        let plain = root.create("plain", FileType::File, 0o644).unwrap();
+        plain.write_at(0, b"plain").unwrap();
+        let block_of = |inode: &Arc<dyn Inode>| fs.get_inode(inode.metadata().unwrap().inode_id).unwrap().disk_inode.read().direct[0] as usize;
+        let blocks = [block_of(&root), block_of(&critical), block_of(&plain)];
+        let critical_id = critical.metadata().unwrap().inode_id;
+        drop((critical, plain, root, fs));
+
+        let fs = JCBFileSystem::open(device.clone(), clock()).unwrap();
+        let mut buf = [0; 6];
+        assert_eq!(fs.root_inode().unwrap().find("critical").unwrap().read_at(0, &mut buf), Ok(6));
+        assert_eq!(&buf, b"before");
+        drop(fs);
+
+        let flip = |offset: usize| device.0.lock()[offset] ^= 1;
+        // the data of a plain file carries no checksum
+        flip(blocks[2] * BLKSIZE);
+        let fs = JCBFileSystem::open(device.clone(), clock()).unwrap();
+        assert_eq!(fs.root_inode().unwrap().find("plain").unwrap().read_at(0, &mut buf), Ok(5));
+        assert_eq!(&buf[..5], b"qlain");
+        flip(blocks[1] * BLKSIZE);
+        assert_eq!(fs.root_inode().unwrap().find("critical").unwrap().read_at(0, &mut buf), Err(FsError::Corrupted));
+        flip(blocks[1] * BLKSIZE);
+        // the cached inodes are gone, the ones on the disk are read again
+        let (block, offset) = fs.inode_slot(critical_id);
+        flip(block * BLKSIZE + offset + offset_of!(DiskINode, mode));
+        assert_eq!(fs.root_inode().unwrap().find("critical").err(), Some(FsError::Corrupted));
+        flip(block * BLKSIZE + offset + offset_of!(DiskINode, mode));
+        flip(blocks[0] * BLKSIZE + 2 * DIRENT_SIZE + 4);
+        assert_eq!(fs.root_inode().unwrap().find("critical").err(), Some(FsError::Corrupted));
+        flip(blocks[0] * BLKSIZE + 2 * DIRENT_SIZE + 4);
+        drop(fs);
+
+        flip(BLKN_SUPER * BLKSIZE + offset_of!(SuperBlock, info));
+        assert_eq!(JCBFileSystem::open(device, clock()).err(), Some(FsError::Corrupted));
+    }
+
+    #[test]
+    fn inode_table() {
+        let device = MemDevice::new(1 << 20);
+        let options = FormatOptions { inodes: Some(1), ..FormatOptions::default() };
+        let fs = JCBFileSystem::create_with(device.clone(), 1 << 20, options, clock()).unwrap();
+        assert_eq!(fs.stats().inodes, INODES_PER_BLOCK - 1);
+        let root = fs.root_inode().unwrap();
+
+        // small files share the blocks of the table, until it is full
+        let before = fs.stats().free_blocks + root.metadata().unwrap().blocks;
+        for i in 0..INODES_PER_BLOCK - 2 {
+            root.create(&alloc::format!("{}", i), FileType::File, 0o644).unwrap();
+        }
+        // only the entries of the root dir take blocks
+        assert_eq!(fs.stats().free_blocks + root.metadata().unwrap().blocks, before);
+        assert_eq!(fs.stats().free_inodes, 0);
+        assert_eq!(root.create("full", FileType::File, 0o644).err(), Some(FsError::NoDeviceSpace));
+
+        // a removed file gives its slot back
+        root.unlink("0").unwrap();
+        assert_eq!(fs.stats().free_inodes, 1);
+        let file = root.create("again", FileType::File, 0o644).unwrap();
+        file.write_at(0, b"kept").unwrap();
+        let id = file.metadata().unwrap().inode_id;
+        drop((file, root, fs));
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        assert_eq!(fs.stats().free_inodes, 0);
+        let file = fs.root_inode().unwrap().find("again").unwrap();
+        assert_eq!(file.metadata().unwrap().inode_id, id);
+        let mut buf = [0; 4];
+        assert_eq!(file.read_at(0, &mut buf), Ok(4));
+        assert_eq!(&buf, b"kept");
+        assert_eq!(fs.get_inode(0).err(), Some(FsError::InvalidParam));
+        assert_eq!(fs.get_inode(INODES_PER_BLOCK).err(), Some(FsError::InvalidParam));
+    }
+
+    #[test]
+    fn open_wrong_fs() {
+        // not formatted
+        let device = MemDevice::new(1 << 20);
+        assert!(matches!(JCBFileSystem::open(device, clock()), Err(FsError::WrongFs)));
+
+        // device smaller than the image
+        let device = MemDevice::new(1 << 20);
+        drop(JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap());
+        device.0.lock().truncate(1 << 19);
+        assert!(matches!(JCBFileSystem::open(device, clock()), Err(FsError::WrongFs)));
+
+        // free map disagrees with the super block
+        let device = MemDevice::new(1 << 20);
+        drop(JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap());
+        device.0.lock()[BLKN_FREEMAP * BLKSIZE] |= 1;
+        assert!(matches!(JCBFileSystem::open(device, clock()), Err(FsError::WrongFs)));
+
+        // so does the inode map, the root dir is never free
+        let device = MemDevice::new(1 << 20);
+        drop(JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap());
+        device.0.lock()[2 * BLKSIZE] |= 1 << INO_ROOT;
+        assert!(matches!(JCBFileSystem::open(device, clock()), Err(FsError::WrongFs)));
+
+        // a feature this version does not know
+        let device = MemDevice::new(1 << 20);
+        drop(JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap());
+        let mut super_block = (device.clone() as Arc<dyn Device>).load_struct::<SuperBlock>(BLKN_SUPER, 0, false).unwrap();
+        super_block.features = 1 << 31;
+        device.0.lock()[..size_of::<SuperBlock>()].copy_from_slice(super_block.as_buf());
+        assert!(matches!(JCBFileSystem::open(device, clock()), Err(FsError::WrongFs)));
+    }
+
+    #[test]
+    fn broken_structs() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+        let id = fs.root_inode().unwrap().create("broken", FileType::File, 0o644).unwrap().metadata().unwrap().inode_id;
+        let (block, offset) = fs.inode_slot(id);
+        drop(fs);
+
+        // a file type that does not exist
+        device.0.lock()[block * BLKSIZE + offset + offset_of!(DiskINode, type_)] = 7;
+        // a name without its NUL
+        let at = device.0.lock().windows(7).position(|w| w == b"broken\0").unwrap();
+        device.0.lock()[at..at + size_of::<Str256>()].fill(b'x');
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        assert_eq!(fs.get_inode(id).err(), Some(FsError::WrongFs));
+        let root = fs.root_inode().unwrap();
+        assert_eq!(root.get_entry(2), Err(FsError::WrongFs));
+        assert_eq!(root.find("broken").err(), Some(FsError::EntryNotFound));
+    }
+
+    #[test]
+    fn create_dev_nodes() {
+        let device = MemDevice::new(1 << 20);
+        let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+        fs.create_dev_nodes().unwrap();
+        // running it again is harmless
+        fs.create_dev_nodes().unwrap();
+        drop(fs);
+
+        let fs = JCBFileSystem::open(device, clock()).unwrap();
+        let dev = fs.root_inode().unwrap().find("dev").unwrap();
+        for &(name, _, _) in DEV_NODES {
+            assert!(dev.find(name).is_ok());
+        }
+        assert!(dev.get_entry(2 + DEV_NODES.len()).is_err());
+    }
+
+    #[test]
+    fn reproducible() {
+        let image = || {
+            let device = MemDevice::new(1 << 20);
+            let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+            fs.create_dev_nodes().unwrap();
+            let file = fs.root_inode().unwrap().create("file", FileType::File, 0o644).unwrap();
+            file.write_at(0, b"hello").unwrap();
+            drop((file, fs));
+            let data = device.0.lock().clone();
+            data
+        };
+        // not even a padding byte may differ
+        assert!(image() == image());
+    }
+
+    /// a device that fails on demand
+    struct FailingDevice {
+        inner: Arc<MemDevice>,
+        fail: AtomicBool,
+    }
+
+    impl Device for FailingDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> core::result::Result<usize, DevError> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(DevErrorKind::Media.into());
+            }
+            self.inner.read_at(offset, buf)
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> core::result::Result<usize, DevError> {
+            if self.fail.load(Ordering::SeqCst) {
+                return Err(DevErrorKind::Io.into());
+            }
+            self.inner.write_at(offset, buf)
+        }
+        fn sync(&self) -> core::result::Result<(), DevError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn device_error() {
+        let device = Arc::new(FailingDevice { inner: MemDevice::new(1 << 20), fail: AtomicBool::new(false) });
+        let fs = JCBFileSystem::create(device.clone(), 1 << 20, clock()).unwrap();
+        let root = fs.root_inode().unwrap();
+        let file = root.create("file", FileType::File, 0o644).unwrap();
+        file.write_at(0, b"data").unwrap();
+
+        device.fail.store(true, Ordering::SeqCst);
+        assert_eq!(file.write_at(0, b"more"), Err(FsError::DeviceError(DevErrorKind::Io)));
+        assert_eq!(file.read_at(0, &mut [0; 4]), Err(FsError::DeviceError(DevErrorKind::Media)));
+        assert_eq!(root.find("file").err(), Some(FsError::DeviceError(DevErrorKind::Media)));
+        assert_eq!(fs.sync(), Err(FsError::DeviceError(DevErrorKind::Io)));
+        // dropping loses the changes, but does not panic
+        drop(root);
+        assert_eq!(fs.root_inode().err(), Some(FsError::DeviceError(DevErrorKind::Media)));
+        drop((file, fs));
+
+        // short I/O past the end of the device is an error as well
+        assert_eq!(
+            (MemDevice::new(BLKSIZE) as Arc<dyn Device>).read_block(1, 0, &mut [0; 4]),
+            Err(FsError::DeviceError(DevErrorKind::OutOfRange))
+        );
+        assert_eq!(
+            (MemDevice::new(BLKSIZE + 2) as Arc<dyn Device>).write_block(1, 0, &[0; 4]),
+            Err(FsError::DeviceError(DevErrorKind::OutOfRange))
+        );
+        // a short transfer before a failure is the failure
+        let device: Arc<dyn Device> = Arc::new(ShortDevice);
+        assert_eq!(device.read_block(0, 0, &mut [0; 4]), Err(FsError::DeviceError(DevErrorKind::Media)));
+        assert_eq!(device.write_block(0, 0, &[0; 4]), Err(FsError::DeviceError(DevErrorKind::Io)));
+    }
+
+    /// transfers the first two bytes, and fails on the rest
+    struct ShortDevice;
+
+    impl Device for ShortDevice {
+        fn read_at(&self, offset: usize, buf: &mut [u8]) -> core::result::Result<usize, DevError> {
+            match offset {
+                0 => Ok(buf.len().min(2)),
+                _ => Err(DevErrorKind::Media.into()),
+            }
+        }
+        fn write_at(&self, offset: usize, buf: &[u8]) -> core::result::Result<usize, DevError> {
+            match offset {
+                0 => Ok(buf.len().min(2)),
+                _ => Err(DevErrorKind::Io.into()),
+            }
+        }
+        fn sync(&self) -> core::result::Result<(), DevError> {
+            Ok(())
+        }
+    }
+}
@@ -1,17 +1,37 @@
-use alloc::vec::Vec;
 use core::fmt::{Debug, Error, Formatter};
+use core::mem::{offset_of, size_of, size_of_val};
+use core::slice;
 use bitvec::order::Lsb0;
 use bitvec::vec::BitVec;
-use fs_jcb::FileType;
-use crate::util::Dirty;
+use fs_jcb::{Dirty, FileType, FsError, Timespec};
 use alloc::str;
+use static_assertions::{const_assert, const_assert_eq};
+use crate::crc::crc32c;
 
+/// entry (on disk)
+///
+/// a removed entry leaves a free slot behind, so that the position of the others never changes
+#[repr(C)]
 #[derive(Debug,Clone)]
 pub struct DiskEntry{
-    pub inode_id:InodeId,
+    /// inode number, 0 in a free slot
+    pub inode_id:u32,
+    /// file name
     pub name:Str256
 }
 
+impl DiskEntry{
+    pub fn free()->Self{
+        DiskEntry{
+            inode_id: 0,
+            name: Str256::from(""),
+        }
+    }
+    pub fn is_free(&self)->bool{
+        self.inode_id==0
+    }
+}
+
 impl<'a> From<&'a str> for Str256{
     fn from(s :&'a str)->Self{
         let mut ret = [0u8;256];
@@ -20,6 +40,14 @@ impl<'a> From<&'a str> for Str256{
     }
 }
 
+impl<'a> From<&'a str> for Str32{
+    fn from(s :&'a str)->Self{
+        let mut ret = [0u8;32];
+        ret[0..s.len()].copy_from_slice(s.as_ref());
+        Str32(ret)
+    }
+}
+
 #[repr(C)]
 #[derive(Clone)]
 pub struct Str256(pub [u8; 256]);
@@ -28,29 +56,46 @@ pub struct Str256(pub [u8; 256]);
 #[derive(Clone)]
 pub struct Str32(pub [u8; 32]);
 
-impl AsRef<str> for Str256 {
-    fn as_ref(&self) -> &str {
-        let len = self.0.iter().enumerate().find(|(_, &b)| b == 0).unwrap().0;
-        str::from_utf8(&self.0[0..len]).unwrap()
+impl Str256 {
+    /// the string up to the NUL, a broken one is `FsError::WrongFs`
+    pub fn as_str(&self) -> fs_jcb::Result<&str> {
+        nul_terminated(&self.0)
+    }
+
+    /// the string is `s`, whatever the bytes after the NUL are
+    pub fn is(&self, s: &str) -> bool {
+        self.0.get(s.len()) == Some(&0) && self.0[..s.len()] == *s.as_bytes()
     }
 }
 
-impl AsRef<str> for Str32 {
-    fn as_ref(&self) -> &str {
-        let len = self.0.iter().enumerate().find(|(_, &b)| b == 0).unwrap().0;
-        str::from_utf8(&self.0[0..len]).unwrap()
+impl Str32 {
+    /// the string up to the NUL, a broken one is `FsError::WrongFs`
+    pub fn as_str(&self) -> fs_jcb::Result<&str> {
+        nul_terminated(&self.0)
     }
 }
 
+/// the UTF-8 string up to the first NUL, which has to be there
+fn nul_terminated(raw: &[u8]) -> fs_jcb::Result<&str> {
+    let len = raw.iter().position(|&b| b == 0).ok_or(FsError::WrongFs)?;
+    str::from_utf8(&raw[..len]).map_err(|_| FsError::WrongFs)
+}
+
 impl Debug for Str256 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{}", self.as_ref())
+        match self.as_str() {
+            Ok(s) => write!(f, "{}", s),
+            Err(_) => write!(f, "{:?}", &self.0[..]),
+        }
     }
 }
 
 impl Debug for Str32 {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
-        write!(f, "{}", self.as_ref())
+        match self.as_str() {
+            Ok(s) => write!(f, "{}", s),
+            Err(_) => write!(f, "{:?}", &self.0[..]),
+        }
     }
 }
 
@@ -68,6 +113,24 @@ pub struct SuperBlock {
     pub info: Str32,
     /// number of freemap blocks
     pub free_map_blocks: u32,
+    /// number of inode slots, slot 0 is never used
+    pub inodes: u32,
+    /// number of unused inode slots
+    pub unused_inodes: u32,
+    /// 1st block of the inode map
+    pub inode_map: u32,
+    /// 1st block of the inode table
+    pub inode_table: u32,
+    /// `FEATURE_*` bits the image is made with
+    pub features: u32,
+    /// 1st block of the journal, with `FEATURE_JOURNAL`
+    pub journal: u32,
+    /// number of journal blocks
+    pub journal_blocks: u32,
+    /// 1st block of the table of block checksums, with `FEATURE_CHECKSUMS`
+    pub checksum_table: u32,
+    /// checksum of the super block, with `FEATURE_CHECKSUMS`
+    pub checksum: u32,
 }
 /// inode (on disk)
 #[repr(C)]
@@ -75,12 +138,20 @@ pub struct SuperBlock {
 pub struct DiskINode {
     /// size of the file (in bytes)
     /// undefined in dir (256 * #entries ?)
-    pub size: u32,
+    pub size: u64,
     /// one of SYS_TYPE_* above
     pub type_: FileType,
+    /// `INODE_*` bits
+    pub flags: u8,
     /// number of hard links to this file
     /// Note: "." and ".." is counted in this nlinks
     pub nlinks: u16,
+    /// permission bits, including setuid/setgid/sticky
+    pub mode: u32,
+    /// owner
+    pub uid: u32,
+    /// owner group
+    pub gid: u32,
     /// number of blocks
     pub blocks: u32,
     /// direct blocks
@@ -89,111 +160,329 @@ pub struct DiskINode {
     pub indirect: u32,
     /// double indirect blocks
     pub db_indirect: u32,
+    /// root of the extent tree, used instead of the blocks above with `INODE_EXTENTS`
+    pub extents: ExtentRoot,
+    /// checksum of the inode with `FEATURE_CHECKSUMS`, 0 otherwise,
+    /// there must be no padding byte left to chance
+    pub checksum: u32,
     /// device inode id for char/block device (major, minor)
     pub device_inode_id: usize,
     /// Time of last access
-    pub atime: Timespec,
+    pub atime: DiskTimespec,
     /// Time of last modification
-    pub mtime: Timespec,
+    pub mtime: DiskTimespec,
     /// Time of last change
-    pub ctime: Timespec,
+    pub ctime: DiskTimespec,
+}
+
+/// `Timespec` as stored on disk
+///
+/// the padding of `Timespec` is spelled out, so that the same inode always gives the same bytes
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct DiskTimespec {
+    pub sec: i64,
+    pub nsec: i32,
+    pub reserved: u32,
+}
+impl DiskTimespec {
+    pub const EPOCH: DiskTimespec = DiskTimespec { sec: 0, nsec: 0, reserved: 0 };
+}
+impl From<Timespec> for DiskTimespec {
+    fn from(time: Timespec) -> Self {
+        DiskTimespec { sec: time.sec, nsec: time.nsec, reserved: 0 }
+    }
+}
+impl From<DiskTimespec> for Timespec {
+    fn from(time: DiskTimespec) -> Self {
+        Timespec { sec: time.sec, nsec: time.nsec }
+    }
 }
 impl DiskINode {
     pub const fn new_file() -> Self {
         DiskINode {
             size: 0,
             type_: FileType::File,
+            flags: 0,
             nlinks: 0,
+            mode: 0o644,
+            uid: 0,
+            gid: 0,
             blocks: 0,
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
+            extents: ExtentRoot::EMPTY,
+            checksum: 0,
             device_inode_id: NODEVICE,
-            atime: Timespec { sec: 0, nsec: 0 },
-            mtime: Timespec { sec: 0, nsec: 0 },
-            ctime: Timespec { sec: 0, nsec: 0 },
+            atime: DiskTimespec::EPOCH,
+            mtime: DiskTimespec::EPOCH,
+            ctime: DiskTimespec::EPOCH,
         }
     }
     pub const fn new_symlink() -> Self {
         DiskINode {
             size: 0,
             type_: FileType::SymLink,
+            flags: 0,
             nlinks: 0,
+            mode: 0o777,
+            uid: 0,
+            gid: 0,
             blocks: 0,
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
+            extents: ExtentRoot::EMPTY,
+            checksum: 0,
             device_inode_id: NODEVICE,
-            atime: Timespec { sec: 0, nsec: 0 },
-            mtime: Timespec { sec: 0, nsec: 0 },
-            ctime: Timespec { sec: 0, nsec: 0 },
+            atime: DiskTimespec::EPOCH,
+            mtime: DiskTimespec::EPOCH,
+            ctime: DiskTimespec::EPOCH,
         }
     }
     pub const fn new_dir() -> Self {
         DiskINode {
             size: 0,
             type_: FileType::Dir,
+            flags: 0,
             nlinks: 0,
+            mode: 0o755,
+            uid: 0,
+            gid: 0,
             blocks: 0,
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
+            extents: ExtentRoot::EMPTY,
+            checksum: 0,
             device_inode_id: NODEVICE,
-            atime: Timespec { sec: 0, nsec: 0 },
-            mtime: Timespec { sec: 0, nsec: 0 },
-            ctime: Timespec { sec: 0, nsec: 0 },
+            atime: DiskTimespec::EPOCH,
+            mtime: DiskTimespec::EPOCH,
+            ctime: DiskTimespec::EPOCH,
         }
     }
     pub const fn new_chardevice(device_inode_id: usize) -> Self {
         DiskINode {
             size: 0,
             type_: FileType::CharDevice,
+            flags: 0,
             nlinks: 0,
+            mode: 0o666,
+            uid: 0,
+            gid: 0,
             blocks: 0,
             direct: [0; NDIRECT],
             indirect: 0,
             db_indirect: 0,
+            extents: ExtentRoot::EMPTY,
+            checksum: 0,
             device_inode_id,
-            atime: Timespec { sec: 0, nsec: 0 },
-            mtime: Timespec { sec: 0, nsec: 0 },
-            ctime: Timespec { sec: 0, nsec: 0 },
+            atime: DiskTimespec::EPOCH,
+            mtime: DiskTimespec::EPOCH,
+            ctime: DiskTimespec::EPOCH,
         }
     }
+    pub const fn new_blockdevice(device_inode_id: usize) -> Self {
+        DiskINode {
+            size: 0,
+            type_: FileType::BlockDevice,
+            flags: 0,
+            nlinks: 0,
+            mode: 0o660,
+            uid: 0,
+            gid: 0,
+            blocks: 0,
+            direct: [0; NDIRECT],
+            indirect: 0,
+            db_indirect: 0,
+            extents: ExtentRoot::EMPTY,
+            checksum: 0,
+            device_inode_id,
+            atime: DiskTimespec::EPOCH,
+            mtime: DiskTimespec::EPOCH,
+            ctime: DiskTimespec::EPOCH,
+        }
+    }
+}
+/// header of a node of an extent tree
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ExtentHeader {
+    /// number of entries in use
+    pub entries: u16,
+    /// number of entries the node has room for
+    pub max: u16,
+    /// 0 in a leaf, the height above the leaves in an index node
+    pub depth: u16,
+    /// always 0
+    pub reserved: u16,
+}
+
+/// entry of a node of an extent tree, sorted by `file_block`
+///
+/// in a leaf, the blocks `file_block..file_block + len` of the file are the blocks `start..start + len`
+/// on the disk, in an index node, the subtree in block `start` maps the file from `file_block` on
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DiskExtent {
+    /// first block of the file
+    pub file_block: u32,
+    /// number of blocks, 0 in an index node
+    pub len: u32,
+    /// first block on the disk, or the child node
+    pub start: u32,
+}
+
+impl DiskExtent {
+    pub const EMPTY: DiskExtent = DiskExtent { file_block: 0, len: 0, start: 0 };
+}
+
+/// root of an extent tree, kept in the inode
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+pub struct ExtentRoot {
+    pub header: ExtentHeader,
+    pub entries: [DiskExtent; EXTENT_ROOT_LEN],
+}
+
+impl ExtentRoot {
+    pub const EMPTY: ExtentRoot = ExtentRoot {
+        header: ExtentHeader { entries: 0, max: EXTENT_ROOT_LEN as u16, depth: 0, reserved: 0 },
+        entries: [DiskExtent::EMPTY; EXTENT_ROOT_LEN],
+    };
+}
+
+/// node of an extent tree below the root, a block on its own
+#[repr(C)]
+pub struct ExtentBlock {
+    pub header: ExtentHeader,
+    pub entries: [DiskExtent; EXTENTS_PER_BLOCK],
+}
+
+/// first block of the journal
+#[repr(C)]
+#[derive(Debug)]
+pub struct JournalHeader {
+    /// JOURNAL_MAGIC
+    pub magic: u32,
+    /// number of journal blocks, this one included
+    pub blocks: u32,
+    /// sequence number of the next transaction, the ones before are home already
+    pub sequence: u32,
+    /// always 0
+    pub reserved: u32,
 }
+
+/// first block of a transaction, followed by the new content of its blocks
+#[repr(C)]
+pub struct JournalDescriptor {
+    /// JOURNAL_DESCRIPTOR_MAGIC
+    pub magic: u32,
+    /// sequence number of the transaction
+    pub sequence: u32,
+    /// number of blocks in the transaction
+    pub count: u32,
+    /// always 0
+    pub reserved: u32,
+    /// where the blocks go
+    pub targets: [u32; JOURNAL_TARGETS],
+}
+
+/// last block of a transaction, the transaction counts only with it
+#[repr(C)]
+#[derive(Debug)]
+pub struct JournalCommit {
+    /// JOURNAL_COMMIT_MAGIC
+    pub magic: u32,
+    /// sequence number of the transaction
+    pub sequence: u32,
+    /// number of blocks in the transaction
+    pub count: u32,
+    /// crc32c of the descriptor and the blocks
+    pub checksum: u32,
+}
+
 pub type BlockId=usize;
 pub type InodeId=usize;
-pub type FreeMap=Dirty<BitVec<Lsb0, u8>>;
+/// bit `i` is set when block (or inode) `i` is free
+pub type FreeMap=Dirty<BitVec<u8, Lsb0>>;
 
 pub trait Alloc{
-    fn alloc(&self)->Option<usize>;
-    fn dealloc(&self,id:usize)->vfs::Result<()>;
+    fn alloc(&mut self)->Option<usize>;
+    fn alloc_near(&mut self,hint:usize)->Option<usize>;
+    fn dealloc(&mut self,id:usize)->fs_jcb::Result<()>;
 }
 impl Alloc for FreeMap{
-    fn alloc(&self) -> Option<usize> {
-        let id=(0..self.len()).find(|&i| self[i]);
+    fn alloc(&mut self) -> Option<usize> {
+        self.alloc_near(0)
+    }
+    /// the first free one from `hint` on, or the first free one at all
+    fn alloc_near(&mut self,hint:usize) -> Option<usize> {
+        let id=self.get(hint..).and_then(|bits| bits.first_one()).map(|id| hint+id)
+            .or_else(|| self.first_one());
         if let Some(alloc_id)=id{
-            self[alloc_id]=false;
+            self.set(alloc_id,false);
         }
         id
     }
-    fn dealloc(&self,id:usize) -> vfs::Result<()> {
-        if id>self.len(){
-            Err()
+    fn dealloc(&mut self,id:usize) -> fs_jcb::Result<()> {
+        if id>=self.len() || self[id]{
+            return Err(FsError::InvalidParam);
         }
-        self[id]=true;
+        self.set(id,true);
         Ok(())
     }
 }
 
+/// view a plain on-disk struct as raw bytes
 pub trait AsBuf{
-    fn as_buf(&self)->&[u8]{
+    /// offset of the checksum field, in the structs carrying one
+    const CHECKSUM: Option<usize> = None;
 
+    fn as_buf(&self)->&[u8]{
+        unsafe { slice::from_raw_parts(self as *const _ as *const u8, size_of_val(self)) }
     }
-    fn as_buf_mut(&self)->&mut [u8]{
+    fn as_buf_mut(&mut self)->&mut [u8]{
+        unsafe { slice::from_raw_parts_mut(self as *mut _ as *mut u8, size_of_val(self)) }
+    }
+    /// checksum of the bytes, the checksum field taken as 0
+    fn checksum(&self)->u32 where Self: Sized{
+        Self::checksum_of(self.as_buf())
+    }
+    /// checksum of the bytes of a struct
+    fn checksum_of(buf:&[u8])->u32 where Self: Sized{
+        match Self::CHECKSUM {
+            Some(at) => crc32c(crc32c(crc32c(0,&buf[..at]),&[0;4]),&buf[at+4..]),
+            None => crc32c(0,buf),
+        }
+    }
+    /// the bytes make a value of the struct, a field of an enum type holds one of its discriminants
+    fn valid(_buf:&[u8])->bool where Self: Sized{
+        true
+    }
+}
+impl AsBuf for SuperBlock{
+    const CHECKSUM: Option<usize> = Some(offset_of!(SuperBlock, checksum));
+}
+impl AsBuf for DiskINode{
+    const CHECKSUM: Option<usize> = Some(offset_of!(DiskINode, checksum));
 
+    fn valid(buf:&[u8])->bool{
+        FileType::try_from(buf[offset_of!(DiskINode, type_)]).is_ok()
     }
 }
 impl AsBuf for DiskEntry{}
+impl AsBuf for ExtentBlock{}
+impl AsBuf for JournalHeader{}
+impl AsBuf for JournalDescriptor{}
+impl AsBuf for JournalCommit{}
+impl AsBuf for u32{}
+
+const_assert_eq!(size_of::<DiskEntry>(), DIRENT_SIZE);
+const_assert!(size_of::<DiskINode>() <= INODE_SIZE);
+const_assert!(size_of::<ExtentBlock>() <= BLKSIZE);
+const_assert_eq!(size_of::<JournalDescriptor>(), BLKSIZE);
 
 
 pub const NODEVICE: usize = 100;
@@ -213,14 +502,44 @@ pub const MAX_INFO_LEN: usize = 31;
 /// max length of filename
 pub const MAX_FNAME_LEN: usize = 255;
 /// max file size in theory (48KB + 4MB + 4GB)
-/// however, the file size is stored in u32
+/// however, the block map only takes files of up to 4GB
 pub const MAX_FILE_SIZE: usize = 0xffffffff;
+/// max file size with `INODE_EXTENTS`, the file blocks are counted in u32
+pub const MAX_EXTENT_FILE_SIZE: u64 = u32::MAX as u64 * BLKSIZE as u64;
+/// the inodes map their blocks with extent trees
+pub const FEATURE_EXTENTS: u32 = 1;
+/// the metadata goes through a journal
+pub const FEATURE_JOURNAL: u32 = 2;
+/// the super block, the inodes, the dir blocks and the blocks of critical files carry checksums
+pub const FEATURE_CHECKSUMS: u32 = 4;
+/// every feature this version knows
+pub const FEATURES: u32 = FEATURE_EXTENTS | FEATURE_JOURNAL | FEATURE_CHECKSUMS;
+/// the inode maps its blocks with an extent tree
+pub const INODE_EXTENTS: u8 = 1;
+/// the data blocks of the file carry checksums like the ones of dirs, and go through the journal
+pub const INODE_CRITICAL: u8 = 2;
+/// number of checksums in a block of the checksum table
+pub const CHECKSUMS_PER_BLOCK: usize = BLKSIZE / size_of::<u32>();
+/// checksum of a block of zeros, the one of a new block
+pub const ZERO_BLOCK_CHECKSUM: u32 = crc32c(0, &[0; BLKSIZE]);
+/// number of extents in the root of the tree
+pub const EXTENT_ROOT_LEN: usize = 4;
+/// number of extents in a block of the tree
+pub const EXTENTS_PER_BLOCK: usize = (BLKSIZE - size_of::<ExtentHeader>()) / size_of::<DiskExtent>();
+/// bits of a mode kept in `DiskINode::mode`
+pub const MODE_MASK: u32 = 0o7777;
 /// block the superblock lives in
 pub const BLKN_SUPER: BlockId = 0;
-/// location of the root dir inode
-pub const BLKN_ROOT: BlockId = 1;
 /// 1st block of the freemap
-pub const BLKN_FREEMAP: BlockId = 2;
+pub const BLKN_FREEMAP: BlockId = 1;
+/// inode of the root dir, inode 0 marks a free dir entry
+pub const INO_ROOT: InodeId = 1;
+/// size of an inode slot in the inode table
+pub const INODE_SIZE: usize = 256;
+/// number of inode slots in a block
+pub const INODES_PER_BLOCK: usize = BLKSIZE / INODE_SIZE;
+/// space per inode when the number of inodes is not given
+pub const BYTES_PER_INODE: usize = 16 << 10;
 /// number of bits in a block
 pub const BLKBITS: usize = BLKSIZE * 8;
 /// size of one entry
@@ -229,10 +548,23 @@ pub const ENTRY_SIZE: usize = 4;
 pub const BLK_NENTRY: usize = BLKSIZE / ENTRY_SIZE;
 /// size of a dirent used in the size field
 pub const DIRENT_SIZE: usize = MAX_FNAME_LEN + 1 + ENTRY_SIZE;
+/// dir entries read at once, a bit more than a block
+pub const DIRENT_BATCH: usize = BLKSIZE / DIRENT_SIZE + 1;
 /// max number of blocks with direct blocks
 pub const MAX_NBLOCK_DIRECT: usize = NDIRECT;
 /// max number of blocks with indirect blocks
 pub const MAX_NBLOCK_INDIRECT: usize = NDIRECT + BLK_NENTRY;
 /// max number of blocks with double indirect blocks
 pub const MAX_NBLOCK_DOUBLE_INDIRECT: usize = NDIRECT + BLK_NENTRY + BLK_NENTRY * BLK_NENTRY;
-
+/// magic number of the journal header
+pub const JOURNAL_MAGIC: u32 = 0x4a4e_4c48;
+/// magic number of a journal descriptor
+pub const JOURNAL_DESCRIPTOR_MAGIC: u32 = 0x4a4e_4c44;
+/// magic number of a journal commit
+pub const JOURNAL_COMMIT_MAGIC: u32 = 0x4a4e_4c43;
+/// max number of blocks in a transaction
+pub const JOURNAL_TARGETS: usize = (BLKSIZE - 16) / 4;
+/// the header, a descriptor, a block and a commit
+pub const MIN_JOURNAL_BLOCKS: usize = 4;
+/// room of a transaction besides the super block and the maps, enough for an operation on the namespace
+pub const JOURNAL_OP_BLOCKS: usize = 32;
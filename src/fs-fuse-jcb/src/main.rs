@@ -1,3 +1,78 @@
+use std::fs::OpenOptions;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::{Arc, Mutex};
+
+use bfs_jcb::JCBFileSystem;
+use bfs_jcb::fsck;
+use fs_jcb::SystemTimeProvider;
+use structopt::StructOpt;
+
+/// exit codes of `fsck`, the ones of e2fsck
+const FSCK_OK: i32 = 0;
+const FSCK_REPAIRED: i32 = 1;
+const FSCK_UNCORRECTED: i32 = 4;
+const FSCK_ERROR: i32 = 8;
+
+#[derive(Debug, StructOpt)]
+#[structopt(version = git_version::git_version!(fallback = "unknown"))]
+enum Opt {
+    /// Check a bfs image, the journal is replayed even if nothing is repaired
+    Fsck {
+        /// Rebuild the maps and the link counts, and link the orphans into lost+found
+        #[structopt(short, long)]
+        repair: bool,
+        /// The image file
+        #[structopt(parse(from_os_str))]
+        image: PathBuf,
+    },
+}
+
 fn main() {
-    println!("Hello, world!");
+    env_logger::init();
+    let code = match Opt::from_args() {
+        Opt::Fsck { repair, image } => fsck(&image, repair),
+    };
+    process::exit(code);
+}
+
+fn fsck(image: &Path, repair: bool) -> i32 {
+    let file = match OpenOptions::new().read(true).write(true).open(image) {
+        Ok(file) => file,
+        Err(err) => {
+            eprintln!("{}: {}", image.display(), err);
+            return FSCK_ERROR;
+        }
+    };
+    let fs = match JCBFileSystem::open(Arc::new(Mutex::new(file)), Arc::new(SystemTimeProvider)) {
+        Ok(fs) => fs,
+        Err(err) => {
+            eprintln!("{}: {}", image.display(), err);
+            return FSCK_ERROR;
+        }
+    };
+    let problems = match fsck::check(&fs, repair) {
+        Ok(problems) => problems,
+        Err(err) => {
+            eprintln!("{}: {}", image.display(), err);
+            return FSCK_ERROR;
+        }
+    };
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let stats = fs.stats();
+    println!(
+        "{}: {}/{} inodes, {}/{} blocks",
+        image.display(),
+        stats.inodes - stats.free_inodes,
+        stats.inodes,
+        stats.blocks - stats.free_blocks,
+        stats.blocks,
+    );
+    match problems.len() {
+        0 => FSCK_OK,
+        _ if repair && problems.iter().all(fsck::Problem::repairable) => FSCK_REPAIRED,
+        _ => FSCK_UNCORRECTED,
+    }
 }
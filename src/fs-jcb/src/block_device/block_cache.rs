@@ -19,13 +19,14 @@ struct BufAllocator{
 impl BufAllocator{
     fn alloc(&mut self, capacity:usize) ->Option<usize>{
         if self.current_id<capacity{
-            self.current_id=self.current_id+1;
+            self.current_id+=1;
             return Some(self.current_id-1);
         }
-        return None;
+        None
     }
 }
 
+/// Cache `size` blocks of the device `T`, evicting the least recently used one
 pub struct BlockCache<T:BlockDevice>{
     capacity:usize,
     device:T,
@@ -36,68 +37,70 @@ pub struct BlockCache<T:BlockDevice>{
 }
 
 impl<T:BlockDevice> BlockCache<T>{
+    /// Create a cache of `size` blocks over `dev`
     pub fn new(dev:T,size:usize)->Self{
         Self{
             capacity: size,
             device: dev,
-            bufs: vec![Mutex::new(Buf{
+            bufs: (0..size).map(|_| Mutex::new(Buf{
                 content: vec!(0;1<<T::BLOCK_SIZE_LOG2 as usize),
                 buf_status: BufStatus::Unused
-            }); size],
+            })).collect(),
             lru: Mutex::new(LRU::new(size)),
             map_list: Mutex::new(BTreeMap::new()),
             buf_allocator: Mutex::new(BufAllocator{ current_id: 0})
         }
     }
 
-    fn get_buf(&self,block_id:BlockId)->MutexGuard<Buf>{
+    fn get_buf(&self,block_id:BlockId)->Result<MutexGuard<'_, Buf>>{
         // get buf
-        let (i,buf)=self._get_buf(block_id);
+        let (i,buf)=self._get_buf(block_id)?;
         // update lru
         self.lru.lock().visit(i);
         // update map
         self.map_list.lock().insert(block_id,i);
 
-        buf
+        Ok(buf)
     }
-    fn _get_buf(&self,block_id:BlockId)->(usize,MutexGuard<Buf>){
+    fn _get_buf(&self,block_id:BlockId)->Result<(usize,MutexGuard<'_, Buf>)>{
         if let Some(&id)=self.map_list.lock().get(&block_id){
-            return (id,self.bufs[id].lock())
+            return Ok((id,self.bufs[id].lock()))
         }
         self._get_unused()
     }
-    fn _get_unused(&self)->(usize,MutexGuard<Buf>){
+    fn _get_unused(&self)->Result<(usize,MutexGuard<'_, Buf>)>{
         if let Some(id)=self.buf_allocator.lock().alloc(self.capacity){
-            (id,self.bufs[id].lock())
+            return Ok((id,self.bufs[id].lock()));
         }
 
         let id=self.lru.lock().tail();
         let mut remove_buf =self.bufs[id].lock();
 
-        //write back into the disk
-        self.write_back(&mut remove_buf).expect("write back fail");
+        //write back into the disk, the block stays cached if it fails
+        self.write_back(&mut remove_buf)?;
 
         // remove from map_list
         match remove_buf.buf_status{
-            BufStatus::Valid(block_id) => self.map_list.lock().remove(&block_id),
-            BufStatus::Dirty(block_id) => self.map_list.lock().remove(&block_id),
-            _ =>{}
+            BufStatus::Valid(block_id) | BufStatus::Dirty(block_id) => {
+                self.map_list.lock().remove(&block_id);
+            }
+            BufStatus::Unused =>{}
         }
 
         // change into unused
         remove_buf.buf_status=BufStatus::Unused;
 
-        (id,remove_buf)
+        Ok((id,remove_buf))
     }
     fn write_back(&self,buf:&mut Buf)->Result<()>{
-        if let Some(BufStatus::Dirty(block_id))=buf.buf_status {
-            self.device.write_at(block_id,&mut buf.content)?;
+        if let BufStatus::Dirty(block_id)=buf.buf_status {
+            self.device.write_at(block_id,&buf.content)?;
             buf.buf_status=BufStatus::Valid(block_id);
         }
         Ok(())
     }
     fn fill_unused(&self,block_id: BlockId,buf:&mut Buf)->Result<()>{
-        if let Some(BufStatus::Unused)=buf.buf_status{
+        if let BufStatus::Unused=buf.buf_status{
             self.device.read_at(block_id,&mut buf.content)?;
             buf.buf_status=BufStatus::Valid(block_id);
         }
@@ -115,19 +118,18 @@ impl<T:BlockDevice> BlockCache<T>{
 /// write:
 /// just like read,cpu will first copy user space cache to the kernel cache(page cache just like the follows),and then symbolise a dirty label,
 /// in the write_back process,cpu will send a io signal to the DMA module and make self exit the cpu ........
-///
-///
-
 impl<T:BlockDevice> BlockDevice for BlockCache<T>{
     const BLOCK_SIZE_LOG2: u8 = T::BLOCK_SIZE_LOG2;
 
     fn read_at(&self, block_id: BlockId, dst_buf: &mut [u8]) -> Result<()> {
-        let mut buf=self.get_buf(block_id);
+        let mut buf=self.get_buf(block_id)?;
 
-        // status: Dirty -> Vaild
-        self.write_back(&mut buf).expect("write back failure");
         // status: Unused -> Vaild
-        self.fill_unused(block_id,&mut buf).expect("fill unused failure");
+        if let Err(err)=self.fill_unused(block_id,&mut buf) {
+            // an unused buf must not stay mapped to the block
+            self.map_list.lock().remove(&block_id);
+            return Err(err);
+        }
 
         let len=1<<T::BLOCK_SIZE_LOG2 as usize;
         dst_buf[..len].copy_from_slice(buf.content.as_slice());
@@ -136,7 +138,7 @@ impl<T:BlockDevice> BlockDevice for BlockCache<T>{
     }
 
     fn write_at(&self, block_id: BlockId, from_buf: &[u8]) -> Result<()> {
-        let mut buf=self.get_buf(block_id);
+        let mut buf=self.get_buf(block_id)?;
 
         buf.buf_status=BufStatus::Dirty(block_id);
 
@@ -147,20 +149,20 @@ impl<T:BlockDevice> BlockDevice for BlockCache<T>{
 
     fn sync(&self) -> Result<()> {
         for buf in self.bufs.iter(){
-            self.write_back(&mut buf.lock()).expect("write back fail");
+            self.write_back(&mut buf.lock())?;
         }
-        Ok(())
+        self.device.sync()
     }
 }
 
 
+#[allow(clippy::upper_case_acronyms)]
 struct LRU{
     list:collections::LinkedList<usize>,
 }
 
 impl LRU{
     pub fn new(size:usize)->Self{
-        let arr:Vec<usize>=(0..size).collect();
         let mut lru=LRU{
             list:collections::LinkedList::new()
         };
@@ -170,12 +172,14 @@ impl LRU{
         lru
     }
     pub fn tail(&self)->usize{
-        return *self.list.back().unwrap();
+        *self.list.back().unwrap()
     }
     pub fn visit(&mut self, id:usize){
         //remove the inode
         let (inode,_)=self.list.iter().enumerate().find(|(_,&num)| num==id).unwrap();
-        let remove=self.list.remove(inode);
+        let mut rest=self.list.split_off(inode);
+        let remove=rest.pop_front().unwrap();
+        self.list.append(&mut rest);
         //push to the head
         self.list.push_front(remove);
     }
@@ -184,83 +188,47 @@ impl LRU{
 #[cfg(test)]
 mod test {
     use super::*;
-    use spin::Mutex;
-
-    impl BlockDevice for Mutex<[u8; 16]> {
-        const BLOCK_SIZE_LOG2: u8 = 2;
-        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
-            if block_id >= 4 {
-                return Err(DevError);
-            }
-            let begin = block_id << 2;
-            buf[..4].copy_from_slice(&self.lock().unwrap()[begin..begin + 4]);
-            Ok(())
-        }
-        fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
-            if block_id >= 4 {
-                return Err(DevError);
-            }
-            let begin = block_id << 2;
-            self.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
-            Ok(())
-        }
-        fn sync(&self) -> Result<()> {
-            Ok(())
-        }
-    }
 
     #[test]
     fn read() {
-        let buf: Mutex<[u8; 16]> =
-            Mutex::new([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]);
+        let cache = BlockCache::new(
+            Mutex::new([0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15]),
+            2,
+        );
         let mut res: [u8; 6] = [0; 6];
 
         // all inside
-        let ret = Device::read_at(&buf, 3, &mut res);
+        let ret = Device::read_at(&cache, 3, &mut res);
         assert_eq!(ret, Ok(6));
         assert_eq!(res, [3, 4, 5, 6, 7, 8]);
 
-        // partly inside
-        let ret = Device::read_at(&buf, 11, &mut res);
-        assert_eq!(ret, Ok(5));
-        assert_eq!(res, [11, 12, 13, 14, 15, 8]);
+        // evict and reload
+        let ret = Device::read_at(&cache, 9, &mut res);
+        assert_eq!(ret, Ok(6));
+        assert_eq!(res, [9, 10, 11, 12, 13, 14]);
 
-        // all outside
-        let ret = Device::read_at(&buf, 16, &mut res);
-        assert_eq!(ret, Ok(0));
-        assert_eq!(res, [11, 12, 13, 14, 15, 8]);
+        // the failing block leaves no trace in the cache
+        let ret = BlockDevice::read_at(&cache, 4, &mut res);
+        assert_eq!(ret.map_err(|e| e.kind()), Err(DevErrorKind::OutOfRange));
+        let ret = Device::read_at(&cache, 0, &mut res);
+        assert_eq!(ret, Ok(6));
+        assert_eq!(res, [0, 1, 2, 3, 4, 5]);
     }
 
     #[test]
-    fn write() {
-        let buf: Mutex<[u8; 16]> = Mutex::new([0; 16]);
+    fn write_back() {
+        let cache = BlockCache::new(Mutex::new([0u8; 16]), 4);
         let res: [u8; 6] = [3, 4, 5, 6, 7, 8];
 
-        // all inside
-        let ret = Device::write_at(&buf, 3, &res);
+        let ret = Device::write_at(&cache, 3, &res);
         assert_eq!(ret, Ok(6));
-        assert_eq!(
-            *buf.lock().unwrap(),
-            [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0]
-        );
-
-        // partly inside
-        let ret = Device::write_at(&buf, 11, &res);
-        assert_eq!(ret, Ok(5));
-        assert_eq!(
-            *buf.lock().unwrap(),
-            [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 3, 4, 5, 6, 7]
-        );
+        // nothing hits the device before sync
+        assert_eq!(*cache.device.lock(), [0; 16]);
 
-        // all outside
-        let ret = Device::write_at(&buf, 16, &res);
-        assert_eq!(ret, Ok(0));
+        Device::sync(&cache).unwrap();
         assert_eq!(
-            *buf.lock().unwrap(),
-            [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 3, 4, 5, 6, 7]
+            *cache.device.lock(),
+            [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0]
         );
     }
 }
-
-
-
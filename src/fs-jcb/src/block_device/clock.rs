@@ -0,0 +1,54 @@
+//! Clocks behind the `TimeProvider` trait
+use super::*;
+use spin::Mutex;
+
+/// A clock that only moves when told to
+///
+/// Images built with it are byte-for-byte reproducible
+pub struct ManualClock {
+    now: Mutex<Timespec>,
+}
+
+impl ManualClock {
+    /// Create a clock standing at `now`
+    pub const fn new(now: Timespec) -> Self {
+        ManualClock { now: Mutex::new(now) }
+    }
+
+    /// Move the clock to `now`, backwards is allowed
+    pub fn set(&self, now: Timespec) {
+        *self.now.lock() = now;
+    }
+
+    /// Move the clock forward by `sec` seconds and `nsec` nanoseconds
+    pub fn advance(&self, sec: i64, nsec: i32) {
+        let mut now = self.now.lock();
+        let nsec = now.nsec as i64 + nsec as i64;
+        now.sec += sec + nsec.div_euclid(1_000_000_000);
+        now.nsec = nsec.rem_euclid(1_000_000_000) as i32;
+    }
+}
+
+impl TimeProvider for ManualClock {
+    fn current_time(&self) -> Timespec {
+        *self.now.lock()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn manual_clock() {
+        let clock = ManualClock::new(Timespec { sec: 1, nsec: 0 });
+        assert_eq!(clock.current_time(), Timespec { sec: 1, nsec: 0 });
+        clock.advance(1, 999_999_999);
+        clock.advance(0, 2);
+        assert_eq!(clock.current_time(), Timespec { sec: 3, nsec: 1 });
+        clock.advance(0, -2);
+        assert_eq!(clock.current_time(), Timespec { sec: 2, nsec: 999_999_999 });
+        clock.set(Timespec { sec: 0, nsec: 0 });
+        assert_eq!(clock.current_time(), Timespec { sec: 0, nsec: 0 });
+    }
+}
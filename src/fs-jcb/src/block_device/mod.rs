@@ -1,29 +1,51 @@
 pub mod block_cache;
+pub mod clock;
+pub mod registry;
+#[cfg(feature = "std")]
+pub mod std_impl;
 
-use core::slice::SlicePattern;
-use crate::{util::*, vfs::Timespec};
-
+/// stop at the first failing block: the end of the device is a short transfer of what is done,
+/// any other failure is an error, even after some progress
+macro_rules! try0 {
+    ($len:expr, $res:expr) => {
+        if let Err(err) = $res {
+            if err.kind() != DevErrorKind::OutOfRange {
+                return Err(err);
+            }
+            return Ok($len);
+        }
+    };
+}
 
+use alloc::vec;
+use crate::{util::*, vfs::Timespec};
 
-//pub mod std_impl;
 
 /// A current time provider
 pub trait TimeProvider: Send + Sync {
+    /// Current time of the clock
     fn current_time(&self) -> Timespec;
 }
 
 /// Interface for FS to read & write
 pub trait Device: Send + Sync {
+    /// Read from `offset`, returns the number of bytes read
     fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize>;
+    /// Write to `offset`, returns the number of bytes written
     fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize>;
+    /// Flush everything to the media
     fn sync(&self) -> Result<()>;
 }
 
 /// Device which can only R/W in blocks
 pub trait BlockDevice: Send + Sync {
+    /// log2 of the block size
     const BLOCK_SIZE_LOG2: u8;
+    /// Read a whole block
     fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()>;
+    /// Write a whole block
     fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()>;
+    /// Flush everything to the media
     fn sync(&self) -> Result<()>;
 }
 
@@ -38,13 +60,14 @@ where T:BlockDevice
         };
 
         for range in iter{
+            let len=range.origin_begin()-offset;
             let buf=&mut buf[range.origin_begin()-offset..range.origin_end()-offset];
             if range.is_full() {
-                BlockDevice::read_at(self,range.block,buf)?;
+                try0!(len,BlockDevice::read_at(self,range.block,buf));
             }else{
-                let tmp=&mut [0u8;1<<T::BLOCK_SIZE_LOG2];
-                BlockDevice::read_at(self,range.block,tmp)?;
-                buf.copy_from_slice(tmp[range.begin..range.end].as_slice());
+                let mut tmp=vec![0u8;1<<T::BLOCK_SIZE_LOG2];
+                try0!(len,BlockDevice::read_at(self,range.block,&mut tmp));
+                buf.copy_from_slice(&tmp[range.begin..range.end]);
             }
         }
         Ok(buf.len())
@@ -58,15 +81,16 @@ where T:BlockDevice
         };
 
         for range in iter{
-            let buf=&mut buf[range.origin_begin()-offset..range.origin_end()-offset];
+            let len=range.origin_begin()-offset;
+            let buf=&buf[range.origin_begin()-offset..range.origin_end()-offset];
             if range.is_full() {
-                BlockDevice::write_at(self,range.block,buf)?;
+                try0!(len,BlockDevice::write_at(self,range.block,buf));
             }else{
-                let tmp=&mut [0u8;1<<T::BLOCK_SIZE_LOG2];
-
-                tmp[range.begin..range.end].copy_from_slice(buf.as_slice());
-
-                BlockDevice::write_at(self,range.block,tmp)?;
+                // read-modify-write, keep the rest of the block
+                let mut tmp=vec![0u8;1<<T::BLOCK_SIZE_LOG2];
+                try0!(len,BlockDevice::read_at(self,range.block,&mut tmp));
+                tmp[range.begin..range.end].copy_from_slice(buf);
+                try0!(len,BlockDevice::write_at(self,range.block,&tmp));
             }
         }
         Ok(buf.len())
@@ -79,22 +103,49 @@ where T:BlockDevice
 
 
 
+/// What went wrong on a device
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum DevErrorKind {
+    /// the transfer failed
+    Io,
+    /// the block or offset is past the end of the device
+    OutOfRange,
+    /// the device cannot be written
+    ReadOnly,
+    /// the media is missing, changed or damaged
+    Media,
+}
+
 /// The error type for device.
-#[derive(Debug, PartialEq, Eq)]
-pub struct DevError;
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct DevError {
+    kind: DevErrorKind,
+}
+
+impl DevError {
+    /// An error of `kind`
+    pub const fn new(kind: DevErrorKind) -> Self {
+        DevError { kind }
+    }
+
+    /// What went wrong
+    pub const fn kind(&self) -> DevErrorKind {
+        self.kind
+    }
+}
+
+impl From<DevErrorKind> for DevError {
+    fn from(kind: DevErrorKind) -> Self {
+        DevError::new(kind)
+    }
+}
 
 /// A specialized `Result` type for device.
 pub type Result<T> = core::result::Result<T, DevError>;
 
+/// Index of a block on the device
 pub type BlockId = usize;
 
-macro_rules! try0 {
-    ($len:expr, $res:expr) => {
-        if $res.is_err() {
-            return Ok($len);
-        }
-    };
-}
 
 
 #[cfg(test)]
@@ -106,18 +157,18 @@ mod test {
         const BLOCK_SIZE_LOG2: u8 = 2;
         fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevErrorKind::OutOfRange.into());
             }
             let begin = block_id << 2;
-            buf[..4].copy_from_slice(&self.lock().unwrap()[begin..begin + 4]);
+            buf[..4].copy_from_slice(&self.lock()[begin..begin + 4]);
             Ok(())
         }
         fn write_at(&self, block_id: BlockId, buf: &[u8]) -> Result<()> {
             if block_id >= 4 {
-                return Err(DevError);
+                return Err(DevErrorKind::OutOfRange.into());
             }
             let begin = block_id << 2;
-            self.lock().unwrap()[begin..begin + 4].copy_from_slice(&buf[..4]);
+            self.lock()[begin..begin + 4].copy_from_slice(&buf[..4]);
             Ok(())
         }
         fn sync(&self) -> Result<()> {
@@ -147,6 +198,59 @@ mod test {
         assert_eq!(res, [11, 12, 13, 14, 15, 8]);
     }
 
+    struct Broken;
+
+    impl BlockDevice for Broken {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, _block_id: BlockId, _buf: &mut [u8]) -> Result<()> {
+            Err(DevErrorKind::Media.into())
+        }
+        fn write_at(&self, _block_id: BlockId, _buf: &[u8]) -> Result<()> {
+            Err(DevErrorKind::ReadOnly.into())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// fails from the second block on
+    struct Failing;
+
+    impl BlockDevice for Failing {
+        const BLOCK_SIZE_LOG2: u8 = 2;
+        fn read_at(&self, block_id: BlockId, buf: &mut [u8]) -> Result<()> {
+            if block_id > 0 {
+                return Err(DevErrorKind::Io.into());
+            }
+            buf[..4].fill(0);
+            Ok(())
+        }
+        fn write_at(&self, block_id: BlockId, _buf: &[u8]) -> Result<()> {
+            match block_id {
+                0 => Ok(()),
+                _ => Err(DevErrorKind::Media.into()),
+            }
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn error() {
+        let mut res: [u8; 6] = [0; 6];
+        let ret = Device::read_at(&Broken, 0, &mut res);
+        assert_eq!(ret, Err(DevError::new(DevErrorKind::Media)));
+        let ret = Device::write_at(&Broken, 4, &res);
+        assert_eq!(ret.map_err(|e| e.kind()), Err(DevErrorKind::ReadOnly));
+
+        // a failing disk is never taken for the end of the device
+        let ret = Device::read_at(&Failing, 2, &mut res);
+        assert_eq!(ret, Err(DevError::new(DevErrorKind::Io)));
+        let ret = Device::write_at(&Failing, 0, &[0; 8]);
+        assert_eq!(ret, Err(DevError::new(DevErrorKind::Media)));
+    }
+
     #[test]
     fn write() {
         let buf: Mutex<[u8; 16]> = Mutex::new([0; 16]);
@@ -156,7 +260,7 @@ mod test {
         let ret = Device::write_at(&buf, 3, &res);
         assert_eq!(ret, Ok(6));
         assert_eq!(
-            *buf.lock().unwrap(),
+            *buf.lock(),
             [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 0, 0, 0, 0, 0]
         );
 
@@ -164,7 +268,7 @@ mod test {
         let ret = Device::write_at(&buf, 11, &res);
         assert_eq!(ret, Ok(5));
         assert_eq!(
-            *buf.lock().unwrap(),
+            *buf.lock(),
             [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 3, 4, 5, 6, 7]
         );
 
@@ -172,7 +276,7 @@ mod test {
         let ret = Device::write_at(&buf, 16, &res);
         assert_eq!(ret, Ok(0));
         assert_eq!(
-            *buf.lock().unwrap(),
+            *buf.lock(),
             [0, 0, 0, 3, 4, 5, 6, 7, 8, 0, 0, 3, 4, 5, 6, 7]
         );
     }
@@ -0,0 +1,84 @@
+//! Drivers behind the char/block device nodes
+use super::*;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+use spin::RwLock;
+
+/// Build a raw device id from `major` and `minor`
+pub const fn make_rdev(major: usize, minor: usize) -> usize {
+    (major << 8) | (minor & 0xff)
+}
+
+/// Major number of a raw device id
+pub const fn rdev_major(rdev: usize) -> usize {
+    rdev >> 8
+}
+
+/// Minor number of a raw device id
+pub const fn rdev_minor(rdev: usize) -> usize {
+    rdev & 0xff
+}
+
+/// Map `(major, minor)` to the driver of the device
+#[derive(Default)]
+pub struct DeviceRegistry {
+    devices: RwLock<BTreeMap<(usize, usize), Arc<dyn Device>>>,
+}
+
+impl DeviceRegistry {
+    /// Create an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the driver of `(major, minor)`, returns the one it replaces
+    pub fn register(&self, major: usize, minor: usize, device: Arc<dyn Device>) -> Option<Arc<dyn Device>> {
+        self.devices.write().insert((major, minor), device)
+    }
+
+    /// Remove the driver of `(major, minor)`
+    pub fn unregister(&self, major: usize, minor: usize) -> Option<Arc<dyn Device>> {
+        self.devices.write().remove(&(major, minor))
+    }
+
+    /// Get the driver of `(major, minor)`
+    pub fn get(&self, major: usize, minor: usize) -> Option<Arc<dyn Device>> {
+        self.devices.read().get(&(major, minor)).cloned()
+    }
+
+    /// Get the driver of a raw device id
+    pub fn get_by_rdev(&self, rdev: usize) -> Option<Arc<dyn Device>> {
+        self.get(rdev_major(rdev), rdev_minor(rdev))
+    }
+}
+
+/// `/dev/null`: reads nothing, swallows every write
+pub struct NullDevice;
+
+impl Device for NullDevice {
+    fn read_at(&self, _offset: usize, _buf: &mut [u8]) -> Result<usize> {
+        Ok(0)
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// `/dev/zero`: reads zeros, swallows every write
+pub struct ZeroDevice;
+
+impl Device for ZeroDevice {
+    fn read_at(&self, _offset: usize, buf: &mut [u8]) -> Result<usize> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+    fn write_at(&self, _offset: usize, buf: &[u8]) -> Result<usize> {
+        Ok(buf.len())
+    }
+    fn sync(&self) -> Result<()> {
+        Ok(())
+    }
+}
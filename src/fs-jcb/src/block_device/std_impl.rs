@@ -0,0 +1,99 @@
+//! Implementations on top of the standard library
+use super::*;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+impl From<io::Error> for DevError {
+    fn from(err: io::Error) -> Self {
+        match err.kind() {
+            ErrorKind::UnexpectedEof => DevErrorKind::OutOfRange,
+            ErrorKind::PermissionDenied | ErrorKind::ReadOnlyFilesystem => DevErrorKind::ReadOnly,
+            ErrorKind::NotFound => DevErrorKind::Media,
+            _ => DevErrorKind::Io,
+        }
+        .into()
+    }
+}
+
+/// An image file on the host, reading past its end is short
+impl Device for Mutex<File> {
+    fn read_at(&self, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let mut file = self.lock().unwrap();
+        file.seek(SeekFrom::Start(offset as u64))?;
+        let mut len = 0;
+        while len < buf.len() {
+            match file.read(&mut buf[len..]) {
+                Ok(0) => break,
+                Ok(n) => len += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: usize, buf: &[u8]) -> Result<usize> {
+        let mut file = self.lock().unwrap();
+        file.seek(SeekFrom::Start(offset as u64))?;
+        file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.lock().unwrap().sync_all()?)
+    }
+}
+
+/// The wall clock of the host
+#[derive(Default)]
+pub struct SystemTimeProvider;
+
+impl TimeProvider for SystemTimeProvider {
+    fn current_time(&self) -> Timespec {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(since) => Timespec {
+                sec: since.as_secs() as i64,
+                nsec: since.subsec_nanos() as i32,
+            },
+            // the host clock is set before 1970
+            Err(err) => {
+                let before = err.duration();
+                let (sec, nsec) = (before.as_secs() as i64, before.subsec_nanos() as i32);
+                if nsec == 0 {
+                    Timespec { sec: -sec, nsec: 0 }
+                } else {
+                    Timespec { sec: -sec - 1, nsec: 1_000_000_000 - nsec }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn system_time() {
+        let now = SystemTimeProvider.current_time();
+        assert!(now.sec > 0);
+        assert!((0..1_000_000_000).contains(&now.nsec));
+    }
+
+    #[test]
+    fn file() {
+        let path = std::env::temp_dir().join(format!("fs-jcb-{}.img", std::process::id()));
+        let file = File::options().read(true).write(true).create(true).truncate(true).open(&path).unwrap();
+        let device = Mutex::new(file);
+        assert_eq!(device.write_at(4, b"hello"), Ok(5));
+        let mut buf = [1u8; 16];
+        // the hole before the data reads as zeros, the end of the file is a short read
+        assert_eq!(device.read_at(0, &mut buf), Ok(9));
+        assert_eq!(&buf[..9], b"\0\0\0\0hello");
+        assert_eq!(device.read_at(100, &mut buf), Ok(0));
+        assert_eq!(device.sync(), Ok(()));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,18 +1,19 @@
-#![feature(linked_list_remove)]
-#![feature(slice_pattern)]
 //!An easy file system isolated from the kernel
-#![no_std]
-#![no_std]
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
 #![deny(missing_docs)]
 
 extern crate alloc;
 mod block_device;
 mod vfs;
 mod util;
-pub use vfs::{Inode,FileSystem,Result,FileType,MetaData,De};
-pub use block_device::{BlockDevice,Device};
+pub use vfs::{Inode,FileSystem,Result,FileType,MetaData,FsError,Timespec,errno,path};
+pub use vfs::dir::{DirEntry,ReadDir};
+pub use vfs::file::{FileHandle,OpenFlags,SeekFrom,MAX_FOLLOW};
+pub use vfs::access::{Credentials,check_access,check_sticky,MAY_READ,MAY_WRITE,MAY_EXEC,S_ISVTX};
+pub use block_device::{BlockDevice,Device,BlockId,DevError,DevErrorKind,TimeProvider};
+pub use block_device::block_cache::BlockCache;
+pub use block_device::clock::ManualClock;
+#[cfg(feature = "std")]
+pub use block_device::std_impl::SystemTimeProvider;
+pub use block_device::registry::{DeviceRegistry,NullDevice,ZeroDevice,make_rdev,rdev_major,rdev_minor};
 pub use util::{BlockIter,BlockRange,Dirty,uninit_memory};
-
-
-
-
@@ -83,32 +83,45 @@ impl<T: Debug> Debug for Dirty<T> {
 
 /// Given a range and iterate sub-range for each block
 pub struct BlockIter {
+    /// begin offset of the range
     pub begin: usize,
+    /// end offset of the range
     pub end: usize,
+    /// log2 of the block size
     pub block_size_log2: u8,
 }
 
+/// A sub-range inside one block
 #[derive(Debug, Eq, PartialEq)]
 pub struct BlockRange {
+    /// block id
     pub block: usize,
+    /// begin offset inside the block
     pub begin: usize,
+    /// end offset inside the block
     pub end: usize,
+    /// log2 of the block size
     pub block_size_log2: u8,
 }
 
 impl BlockRange {
+    /// the range is empty
     pub fn is_empty(&self) -> bool {
         self.end == self.begin
     }
+    /// length of the range
     pub fn len(&self) -> usize {
         self.end - self.begin
     }
+    /// the range covers the whole block
     pub fn is_full(&self) -> bool {
         self.len() == (1usize << self.block_size_log2)
     }
+    /// begin offset in the whole device
     pub fn origin_begin(&self) -> usize {
         (self.block << self.block_size_log2) + self.begin
     }
+    /// end offset in the whole device
     pub fn origin_end(&self) -> usize {
         (self.block << self.block_size_log2) + self.end
     }
@@ -0,0 +1,194 @@
+//! Unix permission checks on behalf of a caller
+use super::*;
+
+/// permission to read a file or list a dir
+pub const MAY_READ: u16 = 0o4;
+/// permission to write a file or add/remove entries of a dir
+pub const MAY_WRITE: u16 = 0o2;
+/// permission to execute a file or search a dir
+pub const MAY_EXEC: u16 = 0o1;
+/// only the owners may remove an entry from a dir with this bit set
+pub const S_ISVTX: u16 = 0o1000;
+
+/// Who is asking
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Credentials {
+    /// effective user id
+    pub uid: usize,
+    /// effective group id
+    pub gid: usize,
+    /// supplementary groups
+    pub groups: Vec<usize>,
+}
+
+impl Credentials {
+    /// Credentials of `uid` in the group `gid` only
+    pub const fn new(uid: usize, gid: usize) -> Self {
+        Credentials { uid, gid, groups: Vec::new() }
+    }
+
+    /// Credentials of the superuser
+    pub const fn root() -> Self {
+        Credentials::new(0, 0)
+    }
+
+    /// Whether every check is overridden
+    pub fn is_root(&self) -> bool {
+        self.uid == 0
+    }
+
+    /// Whether `gid` is the group or one of the supplementary groups
+    pub fn in_group(&self, gid: usize) -> bool {
+        self.gid == gid || self.groups.contains(&gid)
+    }
+}
+
+/// Check that `cred` is granted every bit of `mask` (`MAY_*`) on an inode with `meta`
+///
+/// root is granted anything, but executes a file only if someone may execute it
+pub fn check_access(meta: &MetaData, cred: &Credentials, mask: u16) -> Result<()> {
+    if cred.is_root() {
+        if mask & MAY_EXEC == 0 || meta.type_ == FileType::Dir || meta.permission & 0o111 != 0 {
+            return Ok(());
+        }
+        return Err(FsError::PermissionDenied);
+    }
+    let granted = if meta.uid == cred.uid {
+        meta.permission >> 6
+    } else if cred.in_group(meta.gid) {
+        meta.permission >> 3
+    } else {
+        meta.permission
+    } & 0o7;
+    if granted & mask == mask {
+        Ok(())
+    } else {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+/// Check that `cred` may remove the entry of `victim` from `dir`, the sticky bit rule
+///
+/// write and search permission on `dir` is checked separately
+pub fn check_sticky(dir: &MetaData, victim: &MetaData, cred: &Credentials) -> Result<()> {
+    if dir.permission & S_ISVTX == 0 || cred.is_root() || cred.uid == dir.uid || cred.uid == victim.uid {
+        Ok(())
+    } else {
+        Err(FsError::PermissionDenied)
+    }
+}
+
+/// The operations of an inode, checked on behalf of a caller
+impl dyn Inode {
+    /// check `mask` (`MAY_*`) against the current metadata
+    pub fn check_access(&self, cred: &Credentials, mask: u16) -> Result<()> {
+        check_access(&self.metadata()?, cred, mask)
+    }
+
+    /// `find` with search permission on this dir
+    pub fn find_as(&self, cred: &Credentials, name: &str) -> Result<Arc<dyn Inode>> {
+        self.check_access(cred, MAY_EXEC)?;
+        self.find(name)
+    }
+
+    /// `create` with write and search permission on this dir,
+    /// the new inode is owned by the caller
+    pub fn create_as(&self, cred: &Credentials, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn Inode>> {
+        self.check_access(cred, MAY_WRITE | MAY_EXEC)?;
+        let inode = self.create(name, type_, mode)?;
+        let mut meta = inode.metadata()?;
+        meta.uid = cred.uid;
+        meta.gid = cred.gid;
+        inode.set_metadata(&meta)?;
+        Ok(inode)
+    }
+
+    /// `unlink` with write and search permission on this dir and the sticky bit rule
+    pub fn unlink_as(&self, cred: &Credentials, name: &str) -> Result<()> {
+        let dir = self.metadata()?;
+        check_access(&dir, cred, MAY_WRITE | MAY_EXEC)?;
+        check_sticky(&dir, &self.find(name)?.metadata()?, cred)?;
+        self.unlink(name)
+    }
+
+    /// `read_at` with read permission
+    pub fn read_at_as(&self, cred: &Credentials, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        self.check_access(cred, MAY_READ)?;
+        self.read_at(offset, buf)
+    }
+
+    /// `write_at` with write permission
+    pub fn write_at_as(&self, cred: &Credentials, offset: usize, buf: &[u8]) -> Result<usize> {
+        self.check_access(cred, MAY_WRITE)?;
+        self.write_at(offset, buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn meta(type_: FileType, permission: u16, uid: usize, gid: usize) -> MetaData {
+        let zero = Timespec { sec: 0, nsec: 0 };
+        MetaData {
+            size: 0,
+            blk_size: 0,
+            blocks: 0,
+            atime: zero,
+            mtime: zero,
+            ctime: zero,
+            dev: 0,
+            inode_id: 0,
+            type_,
+            permission,
+            uid,
+            gid,
+            rdev: 0,
+        }
+    }
+
+    #[test]
+    fn owner_group_other() {
+        let file = meta(FileType::File, 0o640, 1000, 100);
+        let owner = Credentials::new(1000, 1000);
+        let member = Credentials { uid: 1001, gid: 1001, groups: vec![100] };
+        let other = Credentials::new(1002, 1002);
+
+        assert_eq!(check_access(&file, &owner, MAY_READ | MAY_WRITE), Ok(()));
+        assert_eq!(check_access(&file, &owner, MAY_EXEC), Err(FsError::PermissionDenied));
+        assert_eq!(check_access(&file, &member, MAY_READ), Ok(()));
+        assert_eq!(check_access(&file, &member, MAY_WRITE), Err(FsError::PermissionDenied));
+        assert_eq!(check_access(&file, &other, MAY_READ), Err(FsError::PermissionDenied));
+
+        // the owner class is taken even if it grants less than the others
+        let file = meta(FileType::File, 0o077, 1000, 100);
+        assert_eq!(check_access(&file, &owner, MAY_READ), Err(FsError::PermissionDenied));
+        assert_eq!(check_access(&file, &other, MAY_READ), Ok(()));
+    }
+
+    #[test]
+    fn root() {
+        let root = Credentials::root();
+        let file = meta(FileType::File, 0o000, 1000, 100);
+        assert_eq!(check_access(&file, &root, MAY_READ | MAY_WRITE), Ok(()));
+        assert_eq!(check_access(&file, &root, MAY_EXEC), Err(FsError::PermissionDenied));
+        let file = meta(FileType::File, 0o001, 1000, 100);
+        assert_eq!(check_access(&file, &root, MAY_EXEC), Ok(()));
+        let dir = meta(FileType::Dir, 0o000, 1000, 100);
+        assert_eq!(check_access(&dir, &root, MAY_EXEC), Ok(()));
+    }
+
+    #[test]
+    fn sticky() {
+        let tmp = meta(FileType::Dir, 0o1777, 0, 0);
+        let mine = meta(FileType::File, 0o644, 1000, 100);
+        let owner = Credentials::new(1000, 100);
+        let other = Credentials::new(1001, 100);
+        assert_eq!(check_sticky(&tmp, &mine, &owner), Ok(()));
+        assert_eq!(check_sticky(&tmp, &mine, &other), Err(FsError::PermissionDenied));
+        assert_eq!(check_sticky(&tmp, &mine, &Credentials::root()), Ok(()));
+
+        let dir = meta(FileType::Dir, 0o777, 0, 0);
+        assert_eq!(check_sticky(&dir, &mine, &other), Ok(()));
+    }
+}
@@ -0,0 +1,71 @@
+//! Iterating over the entries of a dir
+use super::*;
+use alloc::collections::VecDeque;
+
+/// An entry of a dir
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DirEntry {
+    /// name of the entry
+    pub name: String,
+    /// inode the entry points to
+    pub inode_id: usize,
+    /// type of that inode
+    pub file_type: FileType,
+}
+
+/// Cursor over the entries of a dir, see `read_dir`
+///
+/// `cookie` tells where the cursor is and can be handed back to `read_dir_from` later,
+/// an entry that is neither added nor removed meanwhile is seen exactly once
+pub struct ReadDir<'a> {
+    dir: &'a dyn Inode,
+    cookie: usize,
+    batch: VecDeque<(DirEntry, usize)>,
+    end: bool,
+}
+
+impl ReadDir<'_> {
+    /// Position of the next entry
+    pub fn cookie(&self) -> usize {
+        self.cookie
+    }
+}
+
+impl Iterator for ReadDir<'_> {
+    type Item = Result<DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.batch.is_empty() && !self.end {
+            match self.dir.read_dir_at(self.cookie) {
+                Ok(batch) => {
+                    self.end = batch.is_empty();
+                    self.batch = batch.into();
+                }
+                Err(e) => {
+                    self.end = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        let (entry, next) = self.batch.pop_front()?;
+        self.cookie = next;
+        Some(Ok(entry))
+    }
+}
+
+impl dyn Inode {
+    /// Iterate over the entries of this dir, "." and ".." included
+    pub fn read_dir(&self) -> ReadDir<'_> {
+        self.read_dir_from(0)
+    }
+
+    /// Iterate over the entries of this dir from `cookie` on, as given by `ReadDir::cookie`
+    pub fn read_dir_from(&self, cookie: usize) -> ReadDir<'_> {
+        ReadDir {
+            dir: self,
+            cookie,
+            batch: VecDeque::new(),
+            end: false,
+        }
+    }
+}
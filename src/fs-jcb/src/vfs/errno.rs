@@ -0,0 +1,188 @@
+//! Linux errno numbers of `FsError`
+use super::*;
+use core::fmt;
+
+/// Operation not permitted
+pub const EPERM: i32 = 1;
+/// No such file or directory
+pub const ENOENT: i32 = 2;
+/// Interrupted system call
+pub const EINTR: i32 = 4;
+/// I/O error
+pub const EIO: i32 = 5;
+/// Bad file number
+pub const EBADF: i32 = 9;
+/// Try again
+pub const EAGAIN: i32 = 11;
+/// Permission denied
+pub const EACCES: i32 = 13;
+/// Device or resource busy
+pub const EBUSY: i32 = 16;
+/// File exists
+pub const EEXIST: i32 = 17;
+/// Cross-device link
+pub const EXDEV: i32 = 18;
+/// No such device
+pub const ENODEV: i32 = 19;
+/// Not a directory
+pub const ENOTDIR: i32 = 20;
+/// Is a directory
+pub const EISDIR: i32 = 21;
+/// Invalid argument
+pub const EINVAL: i32 = 22;
+/// No space left on device
+pub const ENOSPC: i32 = 28;
+/// Read-only file system
+pub const EROFS: i32 = 30;
+/// Too many links
+pub const EMLINK: i32 = 31;
+/// Function not implemented
+pub const ENOSYS: i32 = 38;
+/// Directory not empty
+pub const ENOTEMPTY: i32 = 39;
+/// Too many symbolic links encountered
+pub const ELOOP: i32 = 40;
+/// Not a data message, reported by linux file systems for a bad checksum
+pub const EBADMSG: i32 = 74;
+
+impl FsError {
+    /// The errno reported for the error, as numbered on linux
+    pub const fn as_errno(&self) -> i32 {
+        match self {
+            FsError::NotSupported => ENOSYS,
+            FsError::NotFile => EISDIR,
+            FsError::IsDir => EISDIR,
+            FsError::NotDir => ENOTDIR,
+            FsError::EntryNotFound => ENOENT,
+            FsError::EntryExist => EEXIST,
+            FsError::NotSameFs => EXDEV,
+            FsError::InvalidParam => EINVAL,
+            FsError::NoDeviceSpace => ENOSPC,
+            FsError::DirRemoved => ENOENT,
+            FsError::DirNotEmpty => ENOTEMPTY,
+            FsError::WrongFs => EINVAL,
+            FsError::DeviceError(DevErrorKind::ReadOnly) => EROFS,
+            FsError::DeviceError(_) => EIO,
+            FsError::IOCTLError => EINVAL,
+            FsError::NoDevice => ENODEV,
+            FsError::Again => EAGAIN,
+            FsError::SymLoop => ELOOP,
+            FsError::Busy => EBUSY,
+            FsError::Interrupted => EINTR,
+            FsError::PermissionDenied => EACCES,
+            FsError::BadHandle => EBADF,
+            FsError::Corrupted => EBADMSG,
+            FsError::TooManyLinks => EMLINK,
+        }
+    }
+}
+
+/// Several errors share an errno, the most general one is picked;
+/// an errno no error maps to is given back
+impl TryFrom<i32> for FsError {
+    type Error = i32;
+    fn try_from(errno: i32) -> core::result::Result<Self, i32> {
+        Ok(match errno {
+            ENOSYS => FsError::NotSupported,
+            EISDIR => FsError::IsDir,
+            ENOTDIR => FsError::NotDir,
+            ENOENT => FsError::EntryNotFound,
+            EEXIST => FsError::EntryExist,
+            EXDEV => FsError::NotSameFs,
+            EINVAL => FsError::InvalidParam,
+            ENOSPC => FsError::NoDeviceSpace,
+            ENOTEMPTY => FsError::DirNotEmpty,
+            EIO => FsError::DeviceError(DevErrorKind::Io),
+            EROFS => FsError::DeviceError(DevErrorKind::ReadOnly),
+            ENODEV => FsError::NoDevice,
+            EAGAIN => FsError::Again,
+            ELOOP => FsError::SymLoop,
+            EBUSY => FsError::Busy,
+            EINTR => FsError::Interrupted,
+            EBADMSG => FsError::Corrupted,
+            EACCES | EPERM => FsError::PermissionDenied,
+            EBADF => FsError::BadHandle,
+            EMLINK => FsError::TooManyLinks,
+            _ => return Err(errno),
+        })
+    }
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FsError::NotSupported => "operation not supported",
+            FsError::NotFile => "not a regular file",
+            FsError::IsDir => "is a directory",
+            FsError::NotDir => "not a directory",
+            FsError::EntryNotFound => "no such file or directory",
+            FsError::EntryExist => "file exists",
+            FsError::NotSameFs => "cross-device link",
+            FsError::InvalidParam => "invalid argument",
+            FsError::NoDeviceSpace => "no space left on device",
+            FsError::DirRemoved => "directory has been removed",
+            FsError::DirNotEmpty => "directory not empty",
+            FsError::WrongFs => "wrong file system type or corrupted image",
+            FsError::DeviceError(DevErrorKind::Io) => "device I/O error",
+            FsError::DeviceError(DevErrorKind::OutOfRange) => "access beyond the end of the device",
+            FsError::DeviceError(DevErrorKind::ReadOnly) => "read-only device",
+            FsError::DeviceError(DevErrorKind::Media) => "no media or media error",
+            FsError::IOCTLError => "invalid ioctl request",
+            FsError::NoDevice => "no such device",
+            FsError::Again => "resource temporarily unavailable",
+            FsError::SymLoop => "too many levels of symbolic links",
+            FsError::Busy => "device or resource busy",
+            FsError::Interrupted => "interrupted",
+            FsError::PermissionDenied => "permission denied",
+            FsError::BadHandle => "bad file handle",
+            FsError::Corrupted => "checksum mismatch, the data is corrupted",
+            FsError::TooManyLinks => "too many links",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for FsError {}
+
+#[cfg(feature = "std")]
+impl From<FsError> for std::io::Error {
+    fn from(err: FsError) -> Self {
+        std::io::Error::from_raw_os_error(err.as_errno())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for errno in 0..128 {
+            if let Ok(err) = FsError::try_from(errno) {
+                if errno == EPERM {
+                    assert_eq!(err.as_errno(), EACCES);
+                } else {
+                    assert_eq!(err.as_errno(), errno);
+                }
+            }
+        }
+        assert_eq!(FsError::try_from(ENOENT), Ok(FsError::EntryNotFound));
+        assert_eq!(FsError::DirRemoved.as_errno(), ENOENT);
+        assert_eq!(FsError::DeviceError(DevErrorKind::Media).as_errno(), EIO);
+        assert_eq!(FsError::try_from(EROFS), Ok(FsError::DeviceError(DevErrorKind::ReadOnly)));
+        assert_eq!(FsError::try_from(-1), Err(-1));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(FsError::EntryNotFound.to_string(), "no such file or directory");
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn io_error() {
+        let err = std::io::Error::from(FsError::EntryNotFound);
+        assert_eq!(err.kind(), std::io::ErrorKind::NotFound);
+        assert_eq!(err.raw_os_error(), Some(ENOENT));
+    }
+}
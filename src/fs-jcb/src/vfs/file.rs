@@ -0,0 +1,220 @@
+//! Open file descriptions: an inode with a cursor and the flags it was opened with
+use super::*;
+use super::access::{Credentials, MAY_READ, MAY_WRITE};
+use core::ops::BitOr;
+use spin::Mutex;
+
+/// symlinks followed at most by `open`, as on linux
+pub const MAX_FOLLOW: usize = 40;
+
+/// How a file is opened
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    /// O_RDONLY
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    /// O_WRONLY
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// O_RDWR
+    pub const READ_WRITE: OpenFlags = OpenFlags(Self::READ.0 | Self::WRITE.0);
+    /// O_APPEND, every write goes to the end of the file
+    pub const APPEND: OpenFlags = OpenFlags(1 << 2);
+    /// O_TRUNC, a file opened for writing is emptied
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 3);
+    /// O_CREAT, a missing file is created
+    pub const CREATE: OpenFlags = OpenFlags(1 << 4);
+    /// O_EXCL, with `CREATE`: the file must not exist yet
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 5);
+    /// O_DIRECTORY, the path must be a dir
+    pub const DIRECTORY: OpenFlags = OpenFlags(1 << 6);
+
+    /// Whether every flag of `other` is set
+    pub const fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    /// Whether the handle may be read
+    pub const fn readable(self) -> bool {
+        self.contains(Self::READ)
+    }
+
+    /// Whether the handle may be written
+    pub const fn writable(self) -> bool {
+        self.contains(Self::WRITE)
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+/// Where `FileHandle::seek` counts from
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SeekFrom {
+    /// from the beginning of the file
+    Start(usize),
+    /// from the end of the file
+    End(isize),
+    /// from the cursor
+    Current(isize),
+}
+
+/// An open file: the inode, the cursor and the flags
+pub struct FileHandle {
+    inode: Arc<dyn Inode>,
+    flags: OpenFlags,
+    offset: Mutex<usize>,
+}
+
+impl FileHandle {
+    /// Open `inode` with `flags`, no check is done
+    pub fn new(inode: Arc<dyn Inode>, flags: OpenFlags) -> Self {
+        FileHandle { inode, flags, offset: Mutex::new(0) }
+    }
+
+    /// The inode behind the handle
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    /// The flags the handle was opened with
+    pub fn flags(&self) -> OpenFlags {
+        self.flags
+    }
+
+    /// Read from the cursor, which moves past the bytes read
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize> {
+        if !self.flags.readable() {
+            return Err(FsError::BadHandle);
+        }
+        let mut offset = self.offset.lock();
+        let len = self.inode.read_at(*offset, buf)?;
+        *offset += len;
+        Ok(len)
+    }
+
+    /// Write at the cursor, or at the end with `APPEND`, the cursor moves past the bytes written
+    pub fn write(&self, buf: &[u8]) -> Result<usize> {
+        if !self.flags.writable() {
+            return Err(FsError::BadHandle);
+        }
+        let mut offset = self.offset.lock();
+        let (begin, len) = if self.flags.contains(OpenFlags::APPEND) {
+            self.inode.append(buf)?
+        } else {
+            (*offset, self.inode.write_at(*offset, buf)?)
+        };
+        *offset = begin + len;
+        Ok(len)
+    }
+
+    /// Move the cursor, return its new position
+    ///
+    /// the cursor may go past the end of the file, but not before its beginning
+    pub fn seek(&self, pos: SeekFrom) -> Result<usize> {
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(pos) => Some(pos),
+            SeekFrom::End(delta) => self.inode.metadata()?.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+        };
+        *offset = new.ok_or(FsError::InvalidParam)?;
+        Ok(*offset)
+    }
+}
+
+impl dyn Inode {
+    /// Open `path` relative to this dir on behalf of `cred`, creating it with `mode` if asked to
+    ///
+    /// symlinks are followed, also the last one, which is created at its target if it dangles
+    pub fn open(self: &Arc<Self>, cred: &Credentials, path: &str, flags: OpenFlags, mode: u32) -> Result<FileHandle> {
+        let (dir, name) = self.lookup_parent(cred, path)?;
+        let mut created = false;
+        let inode = match dir.find_by_path_follow(cred, name, MAX_FOLLOW) {
+            Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => return Err(FsError::EntryExist),
+            Ok(inode) => inode,
+            Err(FsError::EntryNotFound) if flags.contains(OpenFlags::CREATE) => {
+                // only a dir may be named with a trailing slash
+                if path.ends_with('/') {
+                    return Err(FsError::IsDir);
+                }
+                // a dangling symlink gets its target created, unless the name has to be new
+                let (dir, name) = if flags.contains(OpenFlags::EXCLUSIVE) {
+                    (dir, String::from(name))
+                } else {
+                    dir.creation_target(cred, name)?
+                };
+                created = true;
+                dir.create_as(cred, &name, FileType::File, mode)?
+            }
+            Err(e) => return Err(e),
+        };
+
+        let type_ = inode.metadata()?.type_;
+        if (flags.contains(OpenFlags::DIRECTORY) || path.ends_with('/')) && type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        if flags.writable() && type_ == FileType::Dir {
+            return Err(FsError::IsDir);
+        }
+        // the creator may use the file whatever `mode` says
+        if !created {
+            if flags.readable() {
+                inode.check_access(cred, MAY_READ)?;
+            }
+            if flags.writable() {
+                inode.check_access(cred, MAY_WRITE)?;
+            }
+        }
+        if flags.writable() && flags.contains(OpenFlags::TRUNCATE) && type_ == FileType::File {
+            inode.resize(0)?;
+        }
+        Ok(FileHandle::new(inode, flags))
+    }
+
+    /// the dir and the name to create a missing `name` of this dir as,
+    /// the target of `name` if it is a symlink, following at most `MAX_FOLLOW` of them
+    fn creation_target(self: &Arc<Self>, cred: &Credentials, name: &str) -> Result<(Arc<dyn Inode>, String)> {
+        let mut dir = self.clone();
+        let mut name = String::from(name);
+        for _ in 0..MAX_FOLLOW {
+            let link = match dir.find_as(cred, &name) {
+                Ok(inode) if inode.metadata()?.type_ == FileType::SymLink => inode,
+                Ok(_) => return Err(FsError::EntryExist),
+                Err(FsError::EntryNotFound) => return Ok((dir, name)),
+                Err(e) => return Err(e),
+            };
+            let target = link.read_link()?;
+            if target.is_empty() {
+                return Err(FsError::EntryNotFound);
+            }
+            if target.ends_with('/') {
+                return Err(FsError::IsDir);
+            }
+            // a relative target starts from the dir of the link
+            let (parent, last) = dir.lookup_parent(cred, &target)?;
+            name = String::from(last);
+            dir = parent;
+        }
+        Err(FsError::SymLoop)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn flags() {
+        let flags = OpenFlags::READ_WRITE | OpenFlags::APPEND;
+        assert!(flags.readable() && flags.writable());
+        assert!(flags.contains(OpenFlags::APPEND));
+        assert!(!flags.contains(OpenFlags::APPEND | OpenFlags::CREATE));
+        assert!(!OpenFlags::WRITE.readable());
+        assert!(!OpenFlags::default().writable());
+    }
+}
@@ -1,110 +1,139 @@
-
+pub mod access;
+pub mod dir;
+pub mod errno;
+pub mod file;
+pub mod path;
 
 use core::any::Any;
-use core::str;
-use crate::block_device::BlockDevice;
 use alloc::{string::String, sync::Arc, vec::Vec};
 use core::result;
+use dir::DirEntry;
+use crate::block_device::{DevError, DevErrorKind};
 
+/// Abstract file system object such as file or directory.
 pub trait Inode: Any + Sync + Send{
 
+    /// get the metadata of this inode
     fn metadata(&self)->Result<MetaData>{
         Err(FsError::NotSupported)
     }
-    fn set_metadata(&self)->Result<()>{
+    /// set the metadata of this inode
+    ///
+    /// only the permission, the owner and the access/modification times are taken,
+    /// the change time becomes the current time
+    fn set_metadata(&self,_metadata:&MetaData)->Result<()>{
         Err(FsError::NotSupported)
     }
 
     /// create new node under the current node
-    fn create(&self,name:&str,type_:FileType,mode: u32)->Option<Arc<dyn Inode>>;
+    fn create(&self,name:&str,type_:FileType,mode: u32)->Result<Arc<dyn Inode>>;
+    /// add a hard link `name` to `other` in the directory
+    fn link(&self,_name:&str,_other:&Arc<dyn Inode>)->Result<()>{
+        Err(FsError::NotSupported)
+    }
+    /// move the entry `old_name` to `new_name` in the directory `target`,
+    /// an existing `new_name` is replaced
+    fn move_(&self,_old_name:&str,_target:&Arc<dyn Inode>,_new_name:&str)->Result<()>{
+        Err(FsError::NotSupported)
+    }
+    /// create a symlink `name` pointing to `target` under the directory
+    fn create_symlink(&self,_name:&str,_target:&str)->Result<Arc<dyn Inode>>{
+        Err(FsError::NotSupported)
+    }
+    /// read the target of the symlink
+    fn read_link(&self)->Result<String>{
+        Err(FsError::NotSupported)
+    }
+    /// remove the entry `name` from the directory, a dir must be empty
+    fn unlink(&self,_name:&str)->Result<()>{
+        Err(FsError::NotSupported)
+    }
+    /// create a char/block device node `name` for the device `rdev` under the directory
+    fn mknod(&self,_name:&str,_type_:FileType,_mode:u32,_rdev:usize)->Result<Arc<dyn Inode>>{
+        Err(FsError::NotSupported)
+    }
     /// find node in the directory by name
     fn find(&self,name:&str)->Result<Arc<dyn Inode>>;
 
+    /// get the name and the metadata of the entry `entry_id` in the directory
     fn get_entry_with_meta_data(&self,entry_id:usize)-> Result<(MetaData, String)>;
 
+    /// get the name of the entry `entry_id` in the directory
     fn get_entry(&self,entry_id:usize)-> Result<String>;
 
-    fn read_at(&self,offset:usize,buf:&[u8])->Result<usize>{Err(FsError::NotSupported)}
+    /// read some entries of the directory from the position `cookie` on, 0 being the first entry;
+    /// each entry comes with the position right after it, no entry at all is the end
+    ///
+    /// the default reads one entry with `get_entry_with_meta_data`, taking the position as `entry_id`
+    fn read_dir_at(&self,cookie:usize)->Result<Vec<(DirEntry,usize)>>{
+        match self.get_entry_with_meta_data(cookie) {
+            Ok((meta,name)) => Ok(alloc::vec![(DirEntry{
+                name,
+                inode_id: meta.inode_id,
+                file_type: meta.type_,
+            },cookie+1)]),
+            Err(FsError::EntryNotFound) => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// read bytes at `offset` into `buf`, return the number of bytes read
+    fn read_at(&self,_offset:usize,_buf:&mut [u8])->Result<usize>{Err(FsError::NotSupported)}
+
+    /// write bytes at `offset` from `buf`, return the number of bytes written
+    fn write_at(&self,_offset:usize,_buf:&[u8])->Result<usize>{Err(FsError::NotSupported)}
 
-    fn write_at(&self,offset:usize,buf:&mut [u8])->Result<usize>{Err(FsError::NotSupported)}
+    /// write `buf` at the end of the file, return the offset it landed at and the number of bytes written
+    ///
+    /// the default is not atomic against other writers, a file system should do better
+    fn append(&self,buf:&[u8])->Result<(usize,usize)>{
+        let offset=self.metadata()?.size;
+        Ok((offset,self.write_at(offset,buf)?))
+    }
 
-    fn resize(&self)->Result<()>{
+    /// resize the file to `len` bytes
+    fn resize(&self,_len:usize)->Result<()>{
+        Err(FsError::NotSupported)
+    }
+    /// sync all data and metadata of the inode to the device
+    fn sync_all(&self)->Result<()>{
         Err(FsError::NotSupported)
     }
+    /// sync the data of the inode to the device
+    fn sync_data(&self)->Result<()>{
+        Err(FsError::NotSupported)
+    }
+    /// get the file system of the inode
     fn fs(&self)->Arc<dyn FileSystem>;
+    /// this is used to implement dynamics cast
+    /// simply return self in the implement of the function
+    fn as_any_ref(&self)->&dyn Any;
 }
 impl dyn Inode{
-    fn list(&self)->Result<Vec<String>>{
-
-        let info=self.metadata()?;
-        if info.type_!=FileType::Dir{
-            return Err(FsError::NotDir)
-        }
-        (0..).map(|id| self.get_entry(id))
-            .take_while(|result| result.is_ok())
-            .filter_map(|result| result.ok())
-            .collect()
-    }
-
-    fn find_by_path(&self,path:&str)->Result<Arc<dyn Inode>>{
-        self.find_by_path_follow(path,0)
-    }
-
-    fn find_by_path_follow(&self,path:&str,follow_times:usize)->Result<Arc<dyn Inode>> {
-        if self.metadata()?.type_ != FileType::Dir {
-            return Err(FsError::NotDir);
-        }
-
-        let (mut result, mut rest_path) = {
-            if let Some(rest) = path.strip_prefix('/') {
-                (self.fs().root_inode(),String::from(rest) )
-            } else {
-                /// TODO FIXME
-                (self.find(".")?,String::from(path))
-            }
-        };
-        while !rest_path.is_empty() {
-            if result.metadata()?.type_==FileType::File {
-                return Err(FsError::NotDir);
-            }
-            let name={
-                if let Some(pos) = rest_path.find('/'){
-                    let clip=String::from(&rest_path[0..pos]);
-                    rest_path= String::from(&rest_path[pos + 1..]);
-                    clip
-                }else{
-                    rest_path.clone()
-                }
-            };
-            let inode=result.find(&name)?;
-            /// implement the function about symlinks
-            if inode.metadata()?.type_==FileType::SymLink&&follow_times>0{
-                let mut buf = [0u8;256];
-
-                let len=result.read_at(0,&buf)?;
-                let link_path=String::from(str::from_utf8(&buf[0..len]).map_err(|e| FsError::NotDir)?);
-
-                let new_path=link_path+"/"+&rest_path;
-
-                return self.find_by_path_follow(&new_path,follow_times-1);
-            }else{
-                result=inode;
-            }
-        }
-        Ok(result)
+    /// downcast the inode to the concrete type of a file system
+    pub fn downcast_ref<T:Inode>(&self)->Option<&T>{
+        self.as_any_ref().downcast_ref::<T>()
     }
 }
 
+/// Abstract file system
 pub trait FileSystem:Sync+Send{
 
-    fn root_inode(&self)->Arc<dyn Inode>;
+    /// get the root inode of the file system, reading it from the device may fail
+    fn root_inode(&self)->Result<Arc<dyn Inode>>;
+    /// write every dirty structure back to the device
+    fn sync(&self)->Result<()>;
 }
 
 impl dyn FileSystem{
 
 }
 
+/// Metadata of INode
+///
+/// Ref: [http://pubs.opengroup.org/onlinepubs/009604499/basedefs/sys/stat.h.html]
 pub struct MetaData{
+    /// Total size, in bytes
     pub size: usize,
     /// A file system-specific preferred I/O block size for this object.
     /// In some file system types, this may vary from file to file.
@@ -134,47 +163,115 @@ pub struct MetaData{
     pub rdev: usize, // (major << 8) | minor
 }
 
-// Note: IOError/NoMemory always lead to a panic since it's hard to recover from it.
-//       We also panic when we can not parse the fs on disk normally
-#[derive(Debug, Eq, PartialEq)]
+// Note: a failing device is reported as DeviceError and never panics,
+//       an image that can not be parsed is reported as WrongFs.
+//       Running out of memory still panics, as everywhere in alloc.
+/// Errors of the file system operations
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FsError {
-    NotSupported,  // E_UNIMP, or E_INVAL
-    NotFile,       // E_ISDIR
-    IsDir,         // E_ISDIR, used only in link
-    NotDir,        // E_NOTDIR
-    EntryNotFound, // E_NOENT
-    EntryExist,    // E_EXIST
-    NotSameFs,     // E_XDEV
-    InvalidParam,  // E_INVAL
-    NoDeviceSpace, // E_NOSPC, but is defined and not used in the original ucore, which uses E_NO_MEM
-    DirRemoved,    // E_NOENT, when the current dir was remove by a previous unlink
-    DirNotEmpty,   // E_NOTEMPTY
-    WrongFs,       // E_INVAL, when we find the content on disk is wrong when opening the device
-    DeviceError,
+    /// E_UNIMP, or E_INVAL
+    NotSupported,
+    /// E_ISDIR
+    NotFile,
+    /// E_ISDIR, used only in link
+    IsDir,
+    /// E_NOTDIR
+    NotDir,
+    /// E_NOENT
+    EntryNotFound,
+    /// E_EXIST
+    EntryExist,
+    /// E_XDEV
+    NotSameFs,
+    /// E_INVAL
+    InvalidParam,
+    /// E_NOSPC, but is defined and not used in the original ucore, which uses E_NO_MEM
+    NoDeviceSpace,
+    /// E_NOENT, when the current dir was remove by a previous unlink
+    DirRemoved,
+    /// E_NOTEMPTY
+    DirNotEmpty,
+    /// E_INVAL, when we find the content on disk is wrong when opening the device
+    WrongFs,
+    /// E_IO, or E_ROFS, the underlying device failed
+    DeviceError(DevErrorKind),
+    /// E_INVAL, bad ioctl request
     IOCTLError,
+    /// E_NODEV
     NoDevice,
-    Again,       // E_AGAIN, when no data is available, never happens in fs
-    SymLoop,     // E_LOOP
-    Busy,        // E_BUSY
-    Interrupted, // E_INTR
+    /// E_AGAIN, when no data is available, never happens in fs
+    Again,
+    /// E_LOOP
+    SymLoop,
+    /// E_BUSY
+    Busy,
+    /// E_INTR
+    Interrupted,
+    /// E_ACCES
+    PermissionDenied,
+    /// E_BADF, the handle is not open for the operation
+    BadHandle,
+    /// E_BADMSG, the content on disk does not match its checksum
+    Corrupted,
+    /// E_MLINK, the link count of the inode is at its maximum
+    TooManyLinks,
+}
+
+impl From<DevError> for FsError {
+    fn from(err: DevError) -> Self {
+        FsError::DeviceError(err.kind())
+    }
 }
 
+/// A point in time, seconds and nanoseconds since the epoch
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug, Hash)]
 pub struct Timespec {
+    /// seconds
     pub sec: i64,
+    /// nanoseconds
     pub nsec: i32,
 }
 
+/// Type of an inode
+///
+/// file systems store the discriminants on the disk, they never change
+#[repr(u8)]
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum FileType {
-    File,
-    Dir,
-    SymLink,
-    CharDevice,
-    BlockDevice,
-    NamedPipe,
-    Socket,
+    /// regular file
+    File = 0,
+    /// directory
+    Dir = 1,
+    /// symbolic link
+    SymLink = 2,
+    /// character device
+    CharDevice = 3,
+    /// block device
+    BlockDevice = 4,
+    /// FIFO
+    NamedPipe = 5,
+    /// unix domain socket
+    Socket = 6,
+}
+
+/// a stored discriminant, anything else is `FsError::InvalidParam`
+impl TryFrom<u8> for FileType {
+    type Error = FsError;
+
+    fn try_from(raw: u8) -> Result<Self> {
+        Ok(match raw {
+            0 => FileType::File,
+            1 => FileType::Dir,
+            2 => FileType::SymLink,
+            3 => FileType::CharDevice,
+            4 => FileType::BlockDevice,
+            5 => FileType::NamedPipe,
+            6 => FileType::Socket,
+            _ => return Err(FsError::InvalidParam),
+        })
+    }
 }
 
+/// A specialized `Result` type for the file system operations.
 pub type Result<T>=result::Result<T,FsError>;
 
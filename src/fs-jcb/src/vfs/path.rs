@@ -0,0 +1,404 @@
+//! Resolving paths to inodes
+//!
+//! A path is resolved from the root when it starts with `/` and from an explicit current dir
+//! otherwise. Empty components from repeated slashes are skipped, `.` stays in the dir and `..`
+//! goes to the parent as the file system reports it. A trailing slash asks for a dir.
+use super::*;
+use access::{Credentials, MAY_EXEC};
+use file::MAX_FOLLOW;
+
+/// Non-empty components of `path`
+pub fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|name| !name.is_empty())
+}
+
+/// Split `path` into the path of its parent and its last component
+///
+/// trailing slashes are dropped and the parent is `""` for the current dir,
+/// a path without components such as `/` ends in `.`
+pub fn split_last(path: &str) -> (&str, &str) {
+    let trimmed = path.trim_end_matches('/');
+    if trimmed.is_empty() {
+        return (&path[..path.len().min(1)], ".");
+    }
+    match trimmed.rfind('/') {
+        Some(pos) => (&trimmed[..pos + 1], &trimmed[pos + 1..]),
+        None => ("", trimmed),
+    }
+}
+
+impl dyn Inode {
+    /// names of the entries in this dir
+    pub fn list(&self) -> Result<Vec<String>> {
+        if self.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        self.read_dir().map(|entry| entry.map(|entry| entry.name)).collect()
+    }
+
+    /// find the inode by `path` relative to this dir, never following a symlink
+    pub fn find_by_path(self: &Arc<Self>, cred: &Credentials, path: &str) -> Result<Arc<dyn Inode>> {
+        self.find_by_path_follow(cred, path, 0)
+    }
+
+    /// find the inode by `path` relative to this dir, following at most `follow_times` symlinks
+    /// on the way, a symlink is never followed when `follow_times` is 0
+    ///
+    /// `cred` needs search permission on every dir on the way
+    pub fn find_by_path_follow(self: &Arc<Self>, cred: &Credentials, path: &str, follow_times: usize) -> Result<Arc<dyn Inode>> {
+        if path.is_empty() {
+            return Err(FsError::EntryNotFound);
+        }
+        self.resolve(cred, path, follow_times, follow_times > 0)
+    }
+
+    /// find the dir that holds the last component of `path`, for callers that create or remove it
+    ///
+    /// symlinks on the way are followed, the last component is returned as is and may be `.` or `..`
+    pub fn lookup_parent<'a>(self: &Arc<Self>, cred: &Credentials, path: &'a str) -> Result<(Arc<dyn Inode>, &'a str)> {
+        if path.is_empty() {
+            return Err(FsError::EntryNotFound);
+        }
+        let (parent, last) = split_last(path);
+        let dir = self.resolve(cred, parent, MAX_FOLLOW, true)?;
+        if dir.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok((dir, last))
+    }
+
+    fn resolve(self: &Arc<Self>, cred: &Credentials, path: &str, follow_times: usize, follow: bool) -> Result<Arc<dyn Inode>> {
+        let mut current = if path.starts_with('/') { self.fs().root_inode()? } else { self.clone() };
+        let mut rest = path.trim_start_matches('/');
+        while !rest.is_empty() {
+            if current.metadata()?.type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            let name = rest.split('/').next().unwrap_or(rest);
+            let tail = &rest[name.len()..];
+            rest = tail.trim_start_matches('/');
+            if name == "." {
+                current.check_access(cred, MAY_EXEC)?;
+                continue;
+            }
+            let inode = current.find_as(cred, name)?;
+            if follow && inode.metadata()?.type_ == FileType::SymLink {
+                if follow_times == 0 {
+                    return Err(FsError::SymLoop);
+                }
+                let target = inode.read_link()?;
+                if target.is_empty() {
+                    return Err(FsError::EntryNotFound);
+                }
+                // a relative target starts from the dir of the link
+                return current.resolve(cred, &(target + tail), follow_times - 1, true);
+            }
+            current = inode;
+        }
+        if path.ends_with('/') && current.metadata()?.type_ != FileType::Dir {
+            return Err(FsError::NotDir);
+        }
+        Ok(current)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use alloc::collections::BTreeMap;
+    use alloc::sync::Weak;
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use spin::Mutex;
+
+    /// A tree of nodes in memory, just enough to walk paths through it
+    struct Node {
+        id: usize,
+        type_: FileType,
+        mode: u16,
+        target: String,
+        me: Weak<Node>,
+        parent: Weak<Node>,
+        root: Weak<Node>,
+        entries: Mutex<BTreeMap<String, Arc<Node>>>,
+    }
+
+    struct Tree(Arc<Node>);
+
+    impl FileSystem for Tree {
+        fn root_inode(&self) -> Result<Arc<dyn Inode>> {
+            Ok(self.0.clone())
+        }
+        fn sync(&self) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+
+    impl Node {
+        fn root() -> Arc<Node> {
+            Arc::new_cyclic(|me: &Weak<Node>| Node::new(FileType::Dir, 0o755, String::new(), me, me.clone(), me.clone()))
+        }
+
+        fn new(type_: FileType, mode: u16, target: String, me: &Weak<Node>, parent: Weak<Node>, root: Weak<Node>) -> Node {
+            Node {
+                id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+                type_,
+                mode,
+                target,
+                me: me.clone(),
+                parent,
+                root,
+                entries: Mutex::new(BTreeMap::new()),
+            }
+        }
+
+        fn add(&self, name: &str, type_: FileType, mode: u16, target: &str) -> Result<Arc<dyn Inode>> {
+            if self.type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            let mut entries = self.entries.lock();
+            if entries.contains_key(name) {
+                return Err(FsError::EntryExist);
+            }
+            let node = Arc::new_cyclic(|me| Node::new(type_, mode, String::from(target), me, self.me.clone(), self.root.clone()));
+            entries.insert(String::from(name), node.clone());
+            Ok(node)
+        }
+    }
+
+    impl Inode for Node {
+        fn metadata(&self) -> Result<MetaData> {
+            let zero = Timespec { sec: 0, nsec: 0 };
+            Ok(MetaData {
+                size: self.target.len(),
+                blk_size: 0,
+                blocks: 0,
+                atime: zero,
+                mtime: zero,
+                ctime: zero,
+                dev: 0,
+                inode_id: self.id,
+                type_: self.type_,
+                permission: self.mode,
+                uid: 0,
+                gid: 0,
+                rdev: 0,
+            })
+        }
+        fn create(&self, name: &str, type_: FileType, mode: u32) -> Result<Arc<dyn Inode>> {
+            self.add(name, type_, mode as u16, "")
+        }
+        fn create_symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>> {
+            self.add(name, FileType::SymLink, 0o777, target)
+        }
+        fn read_link(&self) -> Result<String> {
+            match self.type_ {
+                FileType::SymLink => Ok(self.target.clone()),
+                _ => Err(FsError::InvalidParam),
+            }
+        }
+        fn find(&self, name: &str) -> Result<Arc<dyn Inode>> {
+            if self.type_ != FileType::Dir {
+                return Err(FsError::NotDir);
+            }
+            let node = match name {
+                "." => self.me.upgrade(),
+                ".." => self.parent.upgrade(),
+                _ => self.entries.lock().get(name).cloned(),
+            };
+            node.map(|node| node as Arc<dyn Inode>).ok_or(FsError::EntryNotFound)
+        }
+        fn get_entry_with_meta_data(&self, entry_id: usize) -> Result<(MetaData, String)> {
+            let name = self.get_entry(entry_id)?;
+            Ok((self.find(&name)?.metadata()?, name))
+        }
+        fn get_entry(&self, entry_id: usize) -> Result<String> {
+            match entry_id {
+                0 => Ok(String::from(".")),
+                1 => Ok(String::from("..")),
+                _ => self.entries.lock().keys().nth(entry_id - 2).cloned().ok_or(FsError::EntryNotFound),
+            }
+        }
+        fn fs(&self) -> Arc<dyn FileSystem> {
+            Arc::new(Tree(self.root.upgrade().unwrap()))
+        }
+        fn as_any_ref(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    /// /
+    /// ├── a/
+    /// │   ├── b/
+    /// │   │   └── file
+    /// │   ├── up -> ..
+    /// │   └── abs -> /a/b
+    /// ├── c -> a/b
+    /// ├── loop -> loop
+    /// ├── dangling -> nowhere
+    /// ├── private/ (0o700)
+    /// │   └── secret
+    /// ├── null (char device)
+    /// └── file
+    fn tree() -> Arc<dyn Inode> {
+        let root: Arc<dyn Inode> = Node::root();
+        let a = root.create("a", FileType::Dir, 0o755).unwrap();
+        let b = a.create("b", FileType::Dir, 0o755).unwrap();
+        b.create("file", FileType::File, 0o644).unwrap();
+        a.create_symlink("up", "..").unwrap();
+        a.create_symlink("abs", "/a/b").unwrap();
+        root.create_symlink("c", "a/b").unwrap();
+        root.create_symlink("loop", "loop").unwrap();
+        root.create_symlink("dangling", "nowhere").unwrap();
+        let private = root.create("private", FileType::Dir, 0o700).unwrap();
+        private.create("secret", FileType::File, 0o644).unwrap();
+        root.create("null", FileType::CharDevice, 0o666).unwrap();
+        root.create("file", FileType::File, 0o644).unwrap();
+        root
+    }
+
+    fn id(inode: &Arc<dyn Inode>) -> usize {
+        inode.metadata().unwrap().inode_id
+    }
+
+    #[test]
+    fn split() {
+        assert_eq!(components("//a/./b//c/").collect::<Vec<_>>(), ["a", ".", "b", "c"]);
+        assert_eq!(components("/").count(), 0);
+
+        assert_eq!(split_last("a"), ("", "a"));
+        assert_eq!(split_last("a/b"), ("a/", "b"));
+        assert_eq!(split_last("/a"), ("/", "a"));
+        assert_eq!(split_last("a//b//"), ("a//", "b"));
+        assert_eq!(split_last("../.."), ("../", ".."));
+        assert_eq!(split_last("/"), ("/", "."));
+        assert_eq!(split_last("///"), ("/", "."));
+        assert_eq!(split_last(""), ("", "."));
+    }
+
+    #[test]
+    fn dots_and_slashes() {
+        let cred = Credentials::root();
+        let root = tree();
+        let a = root.find_by_path(&cred, "a").unwrap();
+        let b = root.find_by_path(&cred, "a/b").unwrap();
+        let file = root.find_by_path(&cred, "a/b/file").unwrap();
+
+        for path in ["/", "//", ".", "./", "..", "/..", "a/..", "a/b/../..", "/a/./../."] {
+            assert_eq!(id(&root.find_by_path(&cred, path).unwrap()), id(&root), "{}", path);
+        }
+        for path in ["a/b", "a//b", "/a/b/", "./a/./b/.", "a/b/../b", "//a///b//"] {
+            assert_eq!(id(&root.find_by_path(&cred, path).unwrap()), id(&b), "{}", path);
+        }
+        assert_eq!(id(&root.find_by_path(&cred, "a/./b/file").unwrap()), id(&file));
+        assert_eq!(root.find_by_path(&cred, "").err(), Some(FsError::EntryNotFound));
+        assert_eq!(root.find_by_path(&cred, "a/missing").err(), Some(FsError::EntryNotFound));
+        assert_eq!(root.find_by_path(&cred, "a/missing/..").err(), Some(FsError::EntryNotFound));
+
+        // the current dir is explicit
+        assert_eq!(id(&b.find_by_path(&cred, "file").unwrap()), id(&file));
+        assert_eq!(id(&b.find_by_path(&cred, ".").unwrap()), id(&b));
+        assert_eq!(id(&b.find_by_path(&cred, "..").unwrap()), id(&a));
+        assert_eq!(id(&b.find_by_path(&cred, "/a").unwrap()), id(&a));
+        assert_eq!(id(&file.find_by_path(&cred, "/a/b").unwrap()), id(&b));
+    }
+
+    #[test]
+    fn not_dir() {
+        let cred = Credentials::root();
+        let root = tree();
+        for path in ["file/", "file/.", "file/..", "file/x", "a/b/file/", "a/b/file/x", "null/", "null/x", "./null/.."] {
+            assert_eq!(root.find_by_path(&cred, path).err(), Some(FsError::NotDir), "{}", path);
+        }
+        let file = root.find_by_path(&cred, "file").unwrap();
+        assert_eq!(file.find_by_path(&cred, "x").err(), Some(FsError::NotDir));
+        assert_eq!(file.find_by_path(&cred, ".").err(), Some(FsError::NotDir));
+        // without following, a symlink is not a dir either
+        assert_eq!(root.find_by_path(&cred, "c/file").err(), Some(FsError::NotDir));
+        assert_eq!(root.find_by_path(&cred, "c/").err(), Some(FsError::NotDir));
+    }
+
+    #[test]
+    fn symlinks() {
+        let cred = Credentials::root();
+        let root = tree();
+        let a = root.find_by_path(&cred, "a").unwrap();
+        let b = root.find_by_path(&cred, "a/b").unwrap();
+        let file = root.find_by_path(&cred, "a/b/file").unwrap();
+
+        let c = root.find_by_path(&cred, "c").unwrap();
+        assert_eq!(c.metadata().unwrap().type_, FileType::SymLink);
+        assert_eq!(id(&root.find_by_path_follow(&cred, "c", 1).unwrap()), id(&b));
+        assert_eq!(id(&root.find_by_path_follow(&cred, "c/", 1).unwrap()), id(&b));
+        assert_eq!(id(&root.find_by_path_follow(&cred, "c//file", 1).unwrap()), id(&file));
+        assert_eq!(id(&root.find_by_path_follow(&cred, "a/up/c", 2).unwrap()), id(&b));
+        assert_eq!(root.find_by_path_follow(&cred, "a/up/c", 1).err(), Some(FsError::SymLoop));
+        assert_eq!(root.find_by_path_follow(&cred, "a/up/c/file", 1).err(), Some(FsError::SymLoop));
+        assert_eq!(id(&b.find_by_path_follow(&cred, "../abs/file", 1).unwrap()), id(&file));
+        assert_eq!(id(&root.find_by_path_follow(&cred, "a/abs/..", 1).unwrap()), id(&a));
+
+        assert_eq!(root.find_by_path_follow(&cred, "loop", MAX_FOLLOW).err(), Some(FsError::SymLoop));
+        assert_eq!(root.find_by_path_follow(&cred, "loop/x", MAX_FOLLOW).err(), Some(FsError::SymLoop));
+        assert_eq!(root.find_by_path_follow(&cred, "dangling", MAX_FOLLOW).err(), Some(FsError::EntryNotFound));
+        assert!(root.find_by_path(&cred, "dangling").is_ok());
+    }
+
+    #[test]
+    fn lookup_parent() {
+        let cred = Credentials::root();
+        let root = tree();
+        let a = root.find_by_path(&cred, "a").unwrap();
+        let b = root.find_by_path(&cred, "a/b").unwrap();
+
+        let (dir, last) = root.lookup_parent(&cred, "new").unwrap();
+        assert_eq!((id(&dir), last), (id(&root), "new"));
+        let (dir, last) = root.lookup_parent(&cred, "a/b/new/").unwrap();
+        assert_eq!((id(&dir), last), (id(&b), "new"));
+        let (dir, last) = b.lookup_parent(&cred, "../new").unwrap();
+        assert_eq!((id(&dir), last), (id(&a), "new"));
+        let (dir, last) = b.lookup_parent(&cred, "//new").unwrap();
+        assert_eq!((id(&dir), last), (id(&root), "new"));
+        // symlinks on the way are followed, the last one is not
+        let (dir, last) = root.lookup_parent(&cred, "c/new").unwrap();
+        assert_eq!((id(&dir), last), (id(&b), "new"));
+        let (dir, last) = root.lookup_parent(&cred, "a/up").unwrap();
+        assert_eq!((id(&dir), last), (id(&a), "up"));
+        let (dir, last) = root.lookup_parent(&cred, "a/..").unwrap();
+        assert_eq!((id(&dir), last), (id(&a), ".."));
+        let (dir, last) = a.lookup_parent(&cred, "/").unwrap();
+        assert_eq!((id(&dir), last), (id(&root), "."));
+
+        assert_eq!(root.lookup_parent(&cred, "").err(), Some(FsError::EntryNotFound));
+        assert_eq!(root.lookup_parent(&cred, "missing/new").err(), Some(FsError::EntryNotFound));
+        assert_eq!(root.lookup_parent(&cred, "file/new").err(), Some(FsError::NotDir));
+        assert_eq!(root.lookup_parent(&cred, "null/new").err(), Some(FsError::NotDir));
+        assert_eq!(root.lookup_parent(&cred, "loop/new").err(), Some(FsError::SymLoop));
+        let file = root.find_by_path(&cred, "file").unwrap();
+        assert_eq!(file.lookup_parent(&cred, "new").err(), Some(FsError::NotDir));
+    }
+
+    #[test]
+    fn permission() {
+        let root = tree();
+        let user = Credentials::new(1000, 1000);
+        assert!(root.find_by_path(&user, "private").is_ok());
+        assert_eq!(root.find_by_path(&user, "private/secret").err(), Some(FsError::PermissionDenied));
+        assert_eq!(root.find_by_path(&user, "private/.").err(), Some(FsError::PermissionDenied));
+        // the dir of the last component is left to the caller to check
+        let (dir, last) = root.lookup_parent(&user, "private/new").unwrap();
+        assert_eq!(dir.create_as(&user, last, FileType::File, 0o644).err(), Some(FsError::PermissionDenied));
+        assert_eq!(root.lookup_parent(&user, "private/new/x").err(), Some(FsError::PermissionDenied));
+        assert!(root.find_by_path(&Credentials::root(), "private/secret").is_ok());
+    }
+
+    #[test]
+    fn list() {
+        let cred = Credentials::root();
+        let root = tree();
+        let b = root.find_by_path(&cred, "a/b").unwrap();
+        assert_eq!(b.list().unwrap(), [".", "..", "file"]);
+        assert_eq!(root.list().unwrap().len(), 9);
+        assert_eq!(b.find_by_path(&cred, "file").unwrap().list(), Err(FsError::NotDir));
+    }
+}
@@ -10,7 +10,7 @@ use alloc::{
     sync::{Arc, Weak},
 };
 use core::{any::Any, future::Future, pin::Pin};
-use fs_jcb::{FileSystem, FileType, Inode, MetaData, Result};
+use fs_jcb::{DirEntry, FileSystem, FileType, Inode, MetaData, Result};
 use rcore_fs::vfs::*;
 use spin::{Mutex, RwLock};
 
@@ -24,12 +24,19 @@ pub struct MountFs{
 }
 
 impl FileSystem for MountFs{
-    fn root_inode(&self) -> Arc<dyn Inode> {
+    fn root_inode(&self) -> Result<Arc<dyn Inode>> {
         match &self.self_mountpoint{
             Some(inode)=>inode.fs.root_inode(),
-            None=>self.mountpoint_root_inode()
+            None=>Ok(self.mountpoint_root_inode()?)
         }
     }
+    fn sync(&self) -> Result<()> {
+        self.inner.sync()?;
+        for mount_fs in self.mount_points.read().values() {
+            mount_fs.sync()?;
+        }
+        Ok(())
+    }
 }
 
 impl MountFs{
@@ -49,12 +56,12 @@ impl MountFs{
         }
     }
     /// return the root_inode of the fs
-    fn mountpoint_root_inode(&self) ->Arc<MNode>{
-        MNode{
-            inner: self.inner.root_inode(),
+    fn mountpoint_root_inode(&self) ->Result<Arc<MNode>>{
+        Ok(MNode{
+            inner: self.inner.root_inode()?,
             fs: self.self_ref.upgrade().clone()?,
             self_ref: Weak::default(),
-        }.wrap()
+        }.wrap())
     }
 }
 #[derive(Clone)]
@@ -94,13 +101,13 @@ impl MNode{
 
     fn change_inode(&self)->Option<Arc<MNode>>{
         if let Some(fs)=self.fs.mount_points.read().get(&self.inner.metadata()?.inode_id){
-            Some(fs.mountpoint_root_inode())
+            fs.mountpoint_root_inode().ok()
         }else{
             Some(self.self_ref.upgrade()?.clone())
         }
     }
     fn is_mountpoint_root_inode(&self)->bool{
-        self.fs.mountpoint_root_inode().metadata()?.inode_id==self.metadata()?.inode_id
+        self.fs.mountpoint_root_inode()?.metadata()?.inode_id==self.metadata()?.inode_id
     }
     fn find(&self,name:&str)->Result<Arc<MNode>>{
         match name{
@@ -139,13 +146,39 @@ impl Inode for MNode{
     fn metadata(&self)->Result<MetaData>{
         Ok(())
     }
-    fn set_metadata(&self)->Result<()>{
-        Err(FsError::NotSupported)
+    fn set_metadata(&self, metadata: &MetaData) -> Result<()> {
+        self.inner.set_metadata(metadata)
     }
     fn create(&self, name: &str, type_: FileType, mode: u32) -> Option<Arc<dyn Inode>> {
         todo!()
     }
 
+    fn link(&self, name: &str, other: &Arc<dyn Inode>) -> Result<()> {
+        let other = &other.downcast_ref::<MNode>().ok_or(FsError::NotSameFs)?.inner;
+        self.inner.link(name, other)
+    }
+
+    fn move_(&self, old_name: &str, target: &Arc<dyn Inode>, new_name: &str) -> Result<()> {
+        let target = &target.downcast_ref::<MNode>().ok_or(FsError::NotSameFs)?.inner;
+        self.inner.move_(old_name, target, new_name)
+    }
+
+    fn mknod(&self, name: &str, type_: FileType, mode: u32, rdev: usize) -> Result<Arc<dyn Inode>> {
+        self.inner.mknod(name, type_, mode, rdev)
+    }
+
+    fn create_symlink(&self, name: &str, target: &str) -> Result<Arc<dyn Inode>> {
+        self.inner.create_symlink(name, target)
+    }
+
+    fn read_link(&self) -> Result<String> {
+        self.inner.read_link()
+    }
+
+    fn unlink(&self, name: &str) -> Result<()> {
+        self.inner.unlink(name)
+    }
+
     fn find(&self, name: &str) -> Option<Arc<dyn Inode>> {
 
 
@@ -161,17 +194,40 @@ impl Inode for MNode{
         todo!()
     }
 
-    fn read_at(&self,offset:usize,buf:&[u8])->Result<usize>{Err(FsError::NotSupported)}
+    fn read_dir_at(&self, cookie: usize) -> Result<Vec<(DirEntry, usize)>> {
+        self.inner.read_dir_at(cookie)
+    }
 
-    fn write_at(&self,offset:usize,buf:&mut [u8])->Result<usize>{Err(FsError::NotSupported)}
+    fn read_at(&self,offset:usize,buf:&mut [u8])->Result<usize>{
+        self.inner.read_at(offset,buf)
+    }
+
+    fn append(&self, buf: &[u8]) -> Result<(usize, usize)> {
+        self.inner.append(buf)
+    }
+    fn write_at(&self,offset:usize,buf:&[u8])->Result<usize>{
+        self.inner.write_at(offset,buf)
+    }
 
-    fn resize(&self)->Result<()>{
-        Err(FsError::NotSupported)
+    fn resize(&self,len:usize)->Result<()>{
+        self.inner.resize(len)
+    }
+
+    fn sync_all(&self)->Result<()>{
+        self.inner.sync_all()
+    }
+
+    fn sync_data(&self)->Result<()>{
+        self.inner.sync_data()
     }
 
     fn fs(&self) -> Arc<dyn FileSystem> {
         todo!()
     }
+
+    fn as_any_ref(&self) -> &dyn Any {
+        self
+    }
 }
 
 